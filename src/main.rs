@@ -1,18 +1,156 @@
 use lambda_runtime::{run, service_fn, Error, LambdaEvent};
 use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
-use reqwest::Client;
-use chrono::{Local, Duration};
+use reqwest::{Client, Method, RequestBuilder, StatusCode, Url};
+use chrono::{DateTime, Datelike, Local, Duration, NaiveDate, Utc, Weekday};
 use futures::future::join_all;
+use futures::stream::{self, StreamExt};
 use urlencoding::encode;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::env;
+use std::hash::{Hash, Hasher};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration as StdDuration, Instant};
+use tokio::sync::Semaphore;
+use tokio::time::sleep;
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+use tracing_subscriber::EnvFilter;
 
 #[derive(Deserialize, Serialize, Clone, Debug, Default)]
 struct Payload {
     ticker_symbol: Option<String>,
     api_key: Option<String>,
-    limit: Option<String>,
-    days_forward: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_numeric")]
+    limit: Option<NumericParam<u32>>,
+    #[serde(default, deserialize_with = "deserialize_numeric")]
+    days_forward: Option<NumericParam<i64>>,
     contract_type: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_number_list")]
+    strikes: Option<Vec<f64>>,
+    #[serde(default, deserialize_with = "deserialize_number_list")]
+    strike_offsets: Option<Vec<f64>>,
+    expiration_weekday: Option<String>,
+    precision: Option<String>,
+    data_tier: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_number_list")]
+    iv_history: Option<Vec<f64>>,
+    max_concurrency: Option<String>,
+    expiration_start: Option<String>,
+    expiration_end: Option<String>,
+    min_dte: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    listing_fallback: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    ramp_concurrency: Option<bool>,
+    tag: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    use_ask_for_cost: Option<bool>,
+    sort_by: Option<String>,
+    sort_order: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_numeric")]
+    top_n: Option<NumericParam<usize>>,
+    output_format: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_json_object")]
+    select: Option<Value>,
+    moneyness_pct: Option<String>,
+    max_otm_pct: Option<String>,
+    max_spread_pct: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_string_map")]
+    extra_params: Option<BTreeMap<String, String>>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    fetch_spot: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    include_zero_oi: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    pair_by_strike: Option<bool>,
+    callback_url: Option<String>,
+    max_stale_secs: Option<String>,
+    action: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    include_history: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    include_greeks: Option<bool>,
+    history_days: Option<String>,
+    sample_strikes: Option<String>,
+    seed: Option<String>,
+    locale: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_numeric")]
+    min_gamma: Option<NumericParam<f64>>,
+    #[serde(default, deserialize_with = "deserialize_numeric")]
+    max_gamma: Option<NumericParam<f64>>,
+    #[serde(default, deserialize_with = "deserialize_numeric")]
+    max_cost: Option<NumericParam<f64>>,
+    correlation_id: Option<String>,
+    strategy: Option<String>,
+    api_host: Option<String>,
+    baseline_iv: Option<String>,
+    page_timeout_ms: Option<String>,
+    per_expiration_top_n: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    only_above_baseline: Option<bool>,
+    long_strike: Option<String>,
+    short_strike: Option<String>,
+    max_retries: Option<String>,
+    retry_base_delay_ms: Option<String>,
+    strike_price_gte: Option<String>,
+    strike_price_lte: Option<String>,
+    max_spot_age_secs: Option<String>,
+    strike_source: Option<String>,
+    min_open_interest: Option<String>,
+    min_volume: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    require_complete: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    include_term_structure: Option<bool>,
+    strike_increment: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    bypass_cache: Option<bool>,
+    num_expirations: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    count_before_filter: Option<bool>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    include_resolved_request: Option<bool>,
+    days_back: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_flag")]
+    expired: Option<bool>,
+}
+
+// A numeric parameter, sent either as a JSON number or as a numeric string
+// (headers and query strings can only carry strings). Anything that doesn't
+// parse is kept verbatim so validation can reject it by name instead of it
+// silently taking the default.
+#[derive(Serialize, Clone, Debug, PartialEq)]
+#[serde(untagged)]
+enum NumericParam<T> {
+    Value(T),
+    Invalid(String),
+}
+
+impl<T: FromStr> NumericParam<T> {
+    fn from_value(value: &Value) -> NumericParam<T> {
+        let parsed = match value {
+            Value::Number(n) => n.to_string().parse().ok(),
+            Value::String(s) => s.trim().parse().ok(),
+            _ => None,
+        };
+        match parsed {
+            Some(parsed) => NumericParam::Value(parsed),
+            None => NumericParam::Invalid(value.as_str().map_or_else(|| value.to_string(), |s| s.to_string())),
+        }
+    }
+
+    fn value(&self) -> Option<&T> {
+        match self {
+            NumericParam::Value(value) => Some(value),
+            NumericParam::Invalid(_) => None,
+        }
+    }
 }
 
 #[derive(Serialize)]
@@ -21,258 +159,6512 @@ struct Response {
     response: String,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
-#[serde(untagged)]
-enum RequestData {
-    Payload(Payload),
-    Headers(Headers),
+// Whether the deployment's Polygon plan gets real-time or 15-minute delayed
+// data. REST snapshots come from the main host either way (the plan, not the
+// endpoint, decides the delay; delayed.polygon.io only serves WebSockets), so
+// the tier just labels the response.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DataTier {
+    Realtime,
+    Delayed,
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
-struct Headers {
-    headers: HeaderValues,
+impl DataTier {
+    fn parse(value: &str) -> Option<DataTier> {
+        match value.trim().to_lowercase().as_str() {
+            "realtime" | "real-time" => Some(DataTier::Realtime),
+            "delayed" => Some(DataTier::Delayed),
+            _ => None,
+        }
+    }
+
+    // Falls back to the POLYGON_DATA_TIER env var, then to real-time.
+    fn from_env() -> DataTier {
+        env::var("POLYGON_DATA_TIER")
+            .ok()
+            .and_then(|v| DataTier::parse(&v))
+            .unwrap_or(DataTier::Realtime)
+    }
 }
 
-#[derive(Deserialize, Serialize, Clone, Debug)]
-struct HeaderValues {
-    ticker_symbol: Option<String>,
-    api_key: Option<String>,
-    limit: Option<String>,
-    days_forward: Option<String>,
-    contract_type: Option<String>,
+// Decimal and digit-grouping separators for the string-formatted numbers.
+// The default keeps the historical output: a `.` decimal point and no
+// grouping.
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct NumberLocale {
+    decimal: char,
+    grouping: Option<char>,
+}
+
+impl Default for NumberLocale {
+    fn default() -> Self {
+        NumberLocale { decimal: '.', grouping: None }
+    }
+}
+
+impl NumberLocale {
+    fn parse(tag: &str) -> Option<NumberLocale> {
+        let (grouping, decimal) = match tag.trim().to_lowercase().replace('_', "-").as_str() {
+            "en" | "en-us" | "en-gb" | "en-ca" | "en-au" => (',', '.'),
+            "de" | "de-de" | "de-at" | "es" | "es-es" | "it" | "it-it" | "nl" | "nl-nl" | "pt-br" => ('.', ','),
+            "fr" | "fr-fr" | "fr-ca" => ('\u{202f}', ','),
+            "de-ch" => ('\'', '.'),
+            _ => return None,
+        };
+        Some(NumberLocale { decimal, grouping: Some(grouping) })
+    }
+
+    fn format(self, value: f64, precision: usize) -> String {
+        self.localize(&format!("{:.*}", precision, value))
+    }
+
+    // Rewrites a plain `1234.5`-style number with this locale's separators.
+    fn localize(self, plain: &str) -> String {
+        let (sign, unsigned) = match plain.strip_prefix('-') {
+            Some(rest) => ("-", rest),
+            None => ("", plain),
+        };
+        let (integer, fraction) = match unsigned.split_once('.') {
+            Some((integer, fraction)) => (integer, Some(fraction)),
+            None => (unsigned, None),
+        };
+        let mut out = sign.to_string();
+        for (i, digit) in integer.chars().enumerate() {
+            if let Some(grouping) = self.grouping {
+                if i > 0 && (integer.len() - i) % 3 == 0 {
+                    out.push(grouping);
+                }
+            }
+            out.push(digit);
+        }
+        if let Some(fraction) = fraction {
+            out.push(self.decimal);
+            out.push_str(fraction);
+        }
+        out
+    }
+
+    // Inverse of `localize`, used to sort on formatted fields.
+    fn parse_number(self, formatted: &str) -> Option<f64> {
+        let plain: String = formatted
+            .chars()
+            .filter(|c| Some(*c) != self.grouping)
+            .map(|c| if c == self.decimal { '.' } else { c })
+            .collect();
+        plain.parse().ok()
+    }
+}
+
+const DEFAULT_API_HOST: &str = "https://api.polygon.io";
+
+// Deployment-wide replacement for the Polygon hosts, such as a local mock
+// server in tests. It is trusted as configured (plain http included).
+fn env_base_url() -> Option<String> {
+    env::var("POLYGON_BASE_URL")
+        .ok()
+        .map(|url| url.trim().trim_end_matches('/').to_string())
+        .filter(|url| !url.is_empty())
+}
+
+// Validates a per-request `api_host` override (a bare host or an https URL)
+// against the comma-separated ALLOWED_API_HOSTS env var, and returns it as a
+// base URL. With the env var unset no override is allowed, so the API key is
+// never sent anywhere the deployment didn't opt into.
+fn resolve_api_host(raw: &str) -> Result<String, String> {
+    let raw = raw.trim();
+    let candidate = if raw.contains("://") {
+        raw.to_string()
+    } else {
+        format!("https://{}", raw)
+    };
+    let url = Url::parse(&candidate).map_err(|e| format!("Invalid api_host {:?}: {}", raw, e))?;
+    if url.scheme() != "https" {
+        return Err(format!("api_host {:?} must use https", raw));
+    }
+    let authority = url_authority(&url).ok_or_else(|| format!("api_host {:?} has no host", raw))?;
+    if is_allowed_host("ALLOWED_API_HOSTS", &authority) {
+        Ok(format!("https://{}", authority))
+    } else {
+        Err(format!("api_host {:?} is not in ALLOWED_API_HOSTS", authority))
+    }
+}
+
+// Validates a `callback_url`: it must use https and its host (with any port)
+// must be listed in the comma-separated ALLOWED_CALLBACK_HOSTS env var, so a
+// request can't make the function POST to internal endpoints such as the
+// instance metadata service. With the env var unset no callback is allowed.
+fn validate_callback_url(raw: &str) -> Result<String, String> {
+    let url = Url::parse(raw.trim()).map_err(|e| format!("Invalid callback_url {:?}: {}", raw, e))?;
+    if url.scheme() != "https" {
+        return Err(format!("callback_url {:?} must use https", raw));
+    }
+    let authority = url_authority(&url).ok_or_else(|| format!("callback_url {:?} has no host", raw))?;
+    if is_allowed_host("ALLOWED_CALLBACK_HOSTS", &authority) {
+        Ok(url.to_string())
+    } else {
+        Err(format!("callback_url host {:?} is not in ALLOWED_CALLBACK_HOSTS", authority))
+    }
+}
+
+// `host` or `host:port` when the URL names a port.
+fn url_authority(url: &Url) -> Option<String> {
+    let host = url.host_str()?;
+    Some(match url.port() {
+        Some(port) => format!("{}:{}", host, port),
+        None => host.to_string(),
+    })
+}
+
+fn is_allowed_host(allowlist_var: &str, authority: &str) -> bool {
+    env::var(allowlist_var)
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .any(|allowed| !allowed.is_empty() && allowed.eq_ignore_ascii_case(authority))
+}
+
+// Tolerance used when matching requested strikes against listed ones, since
+// strikes come back from Polygon as floats.
+const STRIKE_EPSILON: f64 = 0.0005;
+
+// Safety cap on how many contracts-endpoint pages a single lookup follows.
+const MAX_LISTING_PAGES: usize = 20;
+
+// Largest `limit` the contracts endpoint accepts for a single page.
+const POLYGON_MAX_PAGE_SIZE: usize = 1000;
+
+// Inclusive expiration range sent to the contracts endpoint.
+#[derive(Clone, Copy, Debug)]
+struct ExpirationWindow {
+    from: NaiveDate,
+    to: NaiveDate,
+}
+
+impl ExpirationWindow {
+    // The window is the intersection of [today + min_dte, today + days_forward]
+    // with the explicit expiration_start/expiration_end bounds, when given.
+    // A `days_back` replaces the lower bound with today - days_back, reaching
+    // into past expirations.
+    fn resolve(
+        today: NaiveDate,
+        days_forward: i64,
+        min_dte: i64,
+        days_back: Option<i64>,
+        expiration_start: Option<NaiveDate>,
+        expiration_end: Option<NaiveDate>,
+    ) -> ExpirationWindow {
+        let mut from = match days_back {
+            Some(days_back) => today - Duration::days(days_back),
+            None => today + Duration::days(min_dte),
+        };
+        let mut to = today + Duration::days(days_forward);
+        if let Some(start) = expiration_start {
+            from = from.max(start);
+        }
+        if let Some(end) = expiration_end {
+            to = to.min(end);
+        }
+        ExpirationWindow { from, to }
+    }
+
+    fn is_empty(self) -> bool {
+        self.from > self.to
+    }
+
+    fn to_json(self) -> Value {
+        json!({
+            "from": self.from.format("%Y-%m-%d").to_string(),
+            "to": self.to.format("%Y-%m-%d").to_string(),
+        })
+    }
+}
+
+// Result of the contracts reference lookup. Listing entries that come back
+// without a ticker can't be fetched, so they're counted rather than
+// silently discarded.
+#[derive(Clone, Debug, Default)]
+struct ContractListing {
+    tickers: Vec<String>,
+    // Raw listing entries keyed by ticker, used when a snapshot can't be
+    // fetched and the caller asked for listing data instead.
+    entries: HashMap<String, Value>,
+    dropped_without_ticker: usize,
+    // Number of listed contracts in the expiration window that pass the
+    // query's filters, `limit` aside. None when the page cap stopped the
+    // count short.
+    total_in_window: Option<usize>,
+    // Whether a page came back with an error status, cutting the listing
+    // short.
+    failed: bool,
+}
+
+// Parameters for one contracts-reference lookup.
+struct ContractQuery<'a> {
+    ticker_symbol: &'a str,
+    limit: usize,
+    window: ExpirationWindow,
+    // An empty contract type asks for both calls and puts.
+    contract_type: &'a str,
+    strikes: &'a [f64],
+    // Inclusive strike bounds, each optional.
+    strike_range: (Option<f64>, Option<f64>),
+    // Polygon's `expired` flag; when set, only expired contracts are listed,
+    // most recent expiration first. None leaves Polygon's default.
+    expired: Option<bool>,
+    // Only strikes that are a multiple of this are kept.
+    strike_increment: Option<f64>,
+    expiration_weekday: Option<Weekday>,
+    // Additional query parameters forwarded verbatim to Polygon.
+    extra_params: &'a BTreeMap<String, String>,
+    // Deadline for each listing page, the first and every `next_url`
+    // follow-up alike. A page that times out is retried like any other
+    // transient failure, so one stalled page can't hang the whole lookup.
+    page_timeout: StdDuration,
+}
+
+const DEFAULT_PAGE_TIMEOUT: StdDuration = StdDuration::from_secs(10);
+
+// How transient Polygon failures (429, 5xx, timeouts and connection errors)
+// are retried. Every attempt sent under the policy, retries included, is
+// counted in `requests_sent`, which backs `api_calls_made`.
+#[derive(Clone, Copy, Debug)]
+struct RetryPolicy<'a> {
+    max_retries: u32,
+    base_delay: StdDuration,
+    requests_sent: &'a AtomicUsize,
+}
+
+const DEFAULT_MAX_RETRIES: u32 = 3;
+const DEFAULT_RETRY_BASE_DELAY: StdDuration = StdDuration::from_millis(200);
+
+// Longest single wait between attempts, including a server's Retry-After,
+// so a retry can't eat the whole Lambda timeout.
+const MAX_RETRY_DELAY: StdDuration = StdDuration::from_secs(10);
+
+fn is_retryable_status(status: StatusCode) -> bool {
+    status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error()
+}
+
+// Token bucket for Polygon's per-minute request caps (Free and Starter
+// plans). The bucket holds a minute's worth of tokens and refills
+// continuously; a request that finds it empty reserves the next token and
+// waits for it instead of failing. It lives for the container, so every
+// request of an invocation, its concurrent snapshot fetches included, draws
+// from the same bucket.
+struct RateLimiter {
+    per_sec: f64,
+    capacity: f64,
+    // Tokens left (negative while requests are queued for future tokens) and
+    // when they were counted.
+    state: Mutex<(f64, Instant)>,
+}
+
+impl RateLimiter {
+    fn new(requests_per_minute: usize) -> RateLimiter {
+        let capacity = requests_per_minute as f64;
+        RateLimiter {
+            per_sec: capacity / 60.0,
+            capacity,
+            state: Mutex::new((capacity, Instant::now())),
+        }
+    }
+
+    async fn acquire(&self) {
+        let wait = {
+            let mut state = self.state.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let now = Instant::now();
+            let (tokens, counted_at) = *state;
+            let refilled = now.duration_since(counted_at).as_secs_f64() * self.per_sec;
+            let tokens = (tokens + refilled).min(self.capacity) - 1.0;
+            *state = (tokens, now);
+            StdDuration::from_secs_f64((-tokens).max(0.0) / self.per_sec)
+        };
+        if !wait.is_zero() {
+            debug!(wait_ms = wait.as_millis() as u64, "Waiting for the Polygon rate limit");
+            sleep(wait).await;
+        }
+    }
+}
+
+static RATE_LIMITER: OnceLock<Option<RateLimiter>> = OnceLock::new();
+
+// The limiter configured by POLYGON_REQUESTS_PER_MINUTE, if any; unset or 0
+// leaves requests unthrottled.
+fn rate_limiter() -> Option<&'static RateLimiter> {
+    RATE_LIMITER
+        .get_or_init(|| {
+            Some(env_limit("POLYGON_REQUESTS_PER_MINUTE", 0))
+                .filter(|&rpm| rpm > 0)
+                .map(RateLimiter::new)
+        })
+        .as_ref()
+}
+
+// Sends a request, retrying retryable failures with exponential backoff and
+// jitter: attempt n waits around base_delay × 2^(n-1), randomized between half
+// and all of it so concurrent requests don't retry in lockstep. A Retry-After
+// header (in seconds) takes precedence. Any other response, including
+// non-retryable 4xx errors, is returned straight away.
+//
+// Only GETs are retried. Anything else is sent once: replaying a request
+// with side effects needs its own idempotency handling (see post_callback).
+//
+// Every attempt, retries included, first takes a token from the rate limiter.
+async fn send_with_retry(request: RequestBuilder, policy: RetryPolicy<'_>) -> Result<reqwest::Response, Error> {
+    let is_get = request
+        .try_clone()
+        .and_then(|clone| clone.build().ok())
+        .is_some_and(|built| built.method() == Method::GET);
+    if !is_get {
+        policy.requests_sent.fetch_add(1, Ordering::Relaxed);
+        return Ok(request.send().await.map_err(reqwest::Error::without_url)?);
+    }
+    let mut attempt = 0;
+    loop {
+        if let Some(limiter) = rate_limiter() {
+            limiter.acquire().await;
+        }
+        policy.requests_sent.fetch_add(1, Ordering::Relaxed);
+        let Some(this_request) = request.try_clone() else {
+            return Ok(request.send().await.map_err(reqwest::Error::without_url)?);
+        };
+        let retry_after = match this_request.send().await {
+            Ok(response) if is_retryable_status(response.status()) && attempt < policy.max_retries => {
+                warn!(status = %response.status(), attempt = attempt + 1, "Retryable status from Polygon");
+                response
+                    .headers()
+                    .get(reqwest::header::RETRY_AFTER)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.trim().parse::<u64>().ok())
+                    .map(StdDuration::from_secs)
+            }
+            Err(e) if (e.is_timeout() || e.is_connect()) && attempt < policy.max_retries => {
+                warn!(attempt = attempt + 1, error = %e.without_url(), "Transient error from Polygon");
+                None
+            }
+            Err(e) if e.is_timeout() => {
+                return Err(format!("Polygon request timed out after {} attempt(s)", attempt + 1).into());
+            }
+            // Errors drop the request URL, which carries the API key.
+            result => return Ok(result.map_err(reqwest::Error::without_url)?),
+        };
+        let delay = retry_after.unwrap_or_else(|| {
+            let backoff = policy.base_delay.saturating_mul(2u32.saturating_pow(attempt));
+            backoff / 2 + backoff.mul_f64(rand::thread_rng().gen_range(0.0..0.5))
+        });
+        sleep(delay.min(MAX_RETRY_DELAY)).await;
+        attempt += 1;
+    }
 }
 
 async fn get_relevant_option_contracts(
     client: &Client,
     api_key: &str,
-    ticker_symbol: &str,
-    limit: &str,
-    days_forward: &str,
-    contract_type: &str,
-) -> Result<Vec<String>, Error> {
-    let base_url = "https://api.polygon.io/v3/reference/options/contracts";
-    let today = Local::now().date_naive();
-    let days_forward_int: i64 = days_forward.parse().unwrap_or(30);
-    let future_date = today + Duration::days(days_forward_int);
-
-    let response = client
-        .get(base_url)
-        .query(&[
-            ("apiKey", api_key),
-            ("underlying_ticker", ticker_symbol),
-            ("limit", limit),
-            ("order", "asc"),
-            ("sort", "expiration_date"),
-            ("expiration_date.gte", &today.format("%Y-%m-%d").to_string()),
-            ("expiration_date.lte", &future_date.format("%Y-%m-%d").to_string()),
-            ("contract_type", contract_type),
-        ])
-        .send()
-        .await?;
+    api_host: &str,
+    contract_query: &ContractQuery<'_>,
+    retry: RetryPolicy<'_>,
+) -> Result<ContractListing, Error> {
+    let base_url = &format!("{}/v3/reference/options/contracts", api_host);
+    let ContractQuery {
+        ticker_symbol,
+        limit,
+        window,
+        contract_type,
+        strikes,
+        strike_range,
+        expired,
+        strike_increment,
+        expiration_weekday,
+        extra_params,
+        page_timeout,
+    } = *contract_query;
 
-    let status = response.status(); // Capture the status code before consuming the response
+    // Polygon serves at most 1000 contracts per page; larger limits are met
+    // by following `next_url`.
+    let max_tickers = limit;
+    let page_size = max_tickers.clamp(1, POLYGON_MAX_PAGE_SIZE);
+    let mut query: Vec<(&str, String)> = vec![
+        ("apiKey", api_key.to_string()),
+        ("underlying_ticker", ticker_symbol.to_string()),
+        ("limit", page_size.to_string()),
+        // Nearest expiration first: the earliest upcoming one, or for
+        // expired contracts the latest to have expired.
+        ("order", if expired == Some(true) { "desc" } else { "asc" }.to_string()),
+        ("sort", "expiration_date".to_string()),
+        ("expiration_date.gte", window.from.format("%Y-%m-%d").to_string()),
+        ("expiration_date.lte", window.to.format("%Y-%m-%d").to_string()),
+    ];
+    if let Some(expired) = expired {
+        query.push(("expired", expired.to_string()));
+    }
+    if !contract_type.is_empty() {
+        query.push(("contract_type", contract_type.to_string()));
+    }
+
+    // Narrow the listing server-side to the strike range, tightened to the
+    // span of requested strikes; the exact matching happens below.
+    let (mut strike_gte, mut strike_lte) = strike_range;
+    if !strikes.is_empty() {
+        let min_strike = strikes.iter().cloned().fold(f64::INFINITY, f64::min) - STRIKE_EPSILON;
+        let max_strike = strikes.iter().cloned().fold(f64::NEG_INFINITY, f64::max) + STRIKE_EPSILON;
+        strike_gte = Some(strike_gte.map_or(min_strike, |gte| gte.max(min_strike)));
+        strike_lte = Some(strike_lte.map_or(max_strike, |lte| lte.min(max_strike)));
+    }
+    if let Some(strike_gte) = strike_gte {
+        query.push(("strike_price.gte", strike_gte.to_string()));
+    }
+    if let Some(strike_lte) = strike_lte {
+        query.push(("strike_price.lte", strike_lte.to_string()));
+    }
+
+    // Extra parameters can add filters but never replace the ones above.
+    for (key, value) in extra_params {
+        if !query.iter().any(|(existing, _)| existing == key) {
+            query.push((key.as_str(), value.clone()));
+        }
+    }
+
+    let mut listing = ContractListing::default();
+    let mut next_url: Option<String> = None;
+    let mut pages = 0;
+    let mut exhausted = false;
+    let mut matched = 0;
+
+    // Follow `next_url` until Polygon stops returning one. Polygon reports no
+    // total, so once `limit` tickers are collected the remaining pages are
+    // still read, just to count the contracts that pass the filters. A page
+    // may be empty yet still point at further results, so only the absence
+    // of `next_url` (or the page cap) ends the loop.
+    loop {
+        let request = match &next_url {
+            None => client.get(base_url).query(&query),
+            Some(url) => {
+                ensure_same_host(url, base_url)?;
+                client.get(url).query(&[("apiKey", api_key)])
+            }
+        }
+        .timeout(page_timeout);
+        // A page read only for the count can fail without costing the
+        // tickers already collected; the total is just left unknown.
+        let counting_only = listing.tickers.len() >= max_tickers;
+        let response = match send_with_retry(request, retry).await {
+            Ok(response) => response,
+            Err(e) if counting_only => {
+                warn!(error = %e, "Stopped counting the contract listing");
+                break;
+            }
+            Err(e) => return Err(e),
+        };
+        pages += 1;
+
+        let status = response.status(); // Capture the status code before consuming the response
+
+        if !status.is_success() {
+            let error_text = response.text().await?;
+            if counting_only {
+                warn!(%status, response = %error_text, "Stopped counting the contract listing");
+                break;
+            }
+            error!(%status, response = %error_text, "Error fetching contracts");
+            listing.failed = true;
+            break;
+        }
 
-    if status.is_success() {
         let data: Value = response.json().await?;
-        let tickers: Vec<String> = data["results"]
+        for contract in data["results"]
             .as_array()
             .unwrap_or(&vec![])
             .iter()
-            .filter_map(|contract| contract["ticker"].as_str().map(|s| s.to_string()))
-            .collect();
-        Ok(tickers)
+            .filter(|contract| strikes.is_empty() || matches_any_strike(contract, strikes))
+            .filter(|contract| {
+                strike_increment.is_none_or(|increment| is_strike_multiple(contract, increment))
+            })
+            .filter(|contract| {
+                expiration_weekday.is_none_or(|weekday| expires_on_weekday(contract, weekday))
+            })
+        {
+            match contract["ticker"].as_str() {
+                Some(ticker) => {
+                    matched += 1;
+                    if listing.tickers.len() < max_tickers {
+                        listing.tickers.push(ticker.to_string());
+                        listing.entries.insert(ticker.to_string(), contract.clone());
+                    }
+                }
+                None => listing.dropped_without_ticker += 1,
+            }
+        }
+
+        next_url = data["next_url"].as_str().map(|s| s.to_string());
+        if next_url.is_none() {
+            exhausted = true;
+            break;
+        }
+        if pages >= MAX_LISTING_PAGES {
+            warn!(pages, "Stopped following next_url");
+            break;
+        }
+    }
+
+    // The total is only known when every page was read.
+    listing.total_in_window = exhausted.then_some(matched);
+    if listing.dropped_without_ticker > 0 {
+        warn!(count = listing.dropped_without_ticker, "Dropped listed contracts without a ticker");
+    }
+    Ok(listing)
+}
+
+// Distinct listed strikes in ascending order.
+fn listing_strike_grid(entries: &HashMap<String, Value>, tickers: &[String]) -> Vec<f64> {
+    let mut strikes: Vec<f64> = tickers
+        .iter()
+        .filter_map(|ticker| entries.get(ticker))
+        .filter_map(|entry| entry["strike_price"].as_f64())
+        .collect();
+    strikes.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    strikes.dedup_by(|a, b| (*a - *b).abs() < STRIKE_EPSILON);
+    strikes.into_iter().map(round_strike).collect()
+}
+
+// Maps offsets such as [-1, 0, 1] ("one strike below ATM, ATM, one above")
+// onto the listed strike grid, where ATM is the listed strike closest to
+// spot. Offsets that run off the end of the grid are skipped.
+fn resolve_strike_offsets(
+    entries: &HashMap<String, Value>,
+    tickers: &[String],
+    spot: f64,
+    offsets: &[i64],
+) -> Vec<f64> {
+    let grid = listing_strike_grid(entries, tickers);
+    let Some(atm_index) = (0..grid.len()).min_by(|&a, &b| {
+        (grid[a] - spot)
+            .abs()
+            .partial_cmp(&(grid[b] - spot).abs())
+            .unwrap_or(std::cmp::Ordering::Equal)
+    }) else {
+        return Vec::new();
+    };
+
+    let mut strikes: Vec<f64> = offsets
+        .iter()
+        .filter_map(|offset| {
+            let index = (atm_index as i64).checked_add(*offset)?;
+            usize::try_from(index).ok().and_then(|i| grid.get(i)).cloned()
+        })
+        .collect();
+    strikes.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    strikes.dedup_by(|a, b| (*a - *b).abs() < STRIKE_EPSILON);
+    strikes
+}
+
+// Picks `sample_size` distinct strikes from the listed contracts for a sparse
+// view of the chain. Strikes are sorted before sampling so the same seed
+// always yields the same selection, and returned in ascending order.
+fn sample_listing_strikes(
+    entries: &HashMap<String, Value>,
+    tickers: &[String],
+    sample_size: usize,
+    rng: &mut StdRng,
+) -> Vec<f64> {
+    let strikes = listing_strike_grid(entries, tickers);
+
+    let mut sampled: Vec<f64> = strikes
+        .choose_multiple(rng, sample_size.min(strikes.len()))
+        .cloned()
+        .collect();
+    sampled.sort_by(|a, b| a.partial_cmp(b).unwrap_or(std::cmp::Ordering::Equal));
+    sampled
+}
+
+// OCC symbols encode strikes to three decimal places, so rounding there strips
+// float noise (150.00000000000001 -> 150) without altering any real strike.
+fn round_strike(strike: f64) -> f64 {
+    (strike * 1000.0).round() / 1000.0
+}
+
+// Expiration dates are calendar dates in exchange (US Eastern) time, so the
+// weekday is read straight off the date with no timezone conversion, which
+// could otherwise shift it by a day.
+fn expires_on_weekday(contract: &Value, weekday: Weekday) -> bool {
+    contract["expiration_date"]
+        .as_str()
+        .and_then(parse_date)
+        .map(|date| date.weekday() == weekday)
+        .unwrap_or(false)
+}
+
+// Accepts a weekday name ("friday", "fri") or a number where 0 is Monday and
+// 6 is Sunday.
+fn parse_weekday(value: &str) -> Option<Weekday> {
+    let value = value.trim();
+    if let Ok(index) = value.parse::<u8>() {
+        return match index {
+            0 => Some(Weekday::Mon),
+            1 => Some(Weekday::Tue),
+            2 => Some(Weekday::Wed),
+            3 => Some(Weekday::Thu),
+            4 => Some(Weekday::Fri),
+            5 => Some(Weekday::Sat),
+            6 => Some(Weekday::Sun),
+            _ => None,
+        };
+    }
+    value.parse::<Weekday>().ok()
+}
+
+// Polygon's `next_url` is followed with the API key attached, so it must stay
+// on the host the lookup started on; anything else is refused rather than
+// sending the key to an unexpected server.
+fn ensure_same_host(next_url: &str, base_url: &str) -> Result<(), Error> {
+    let next = Url::parse(next_url)?;
+    let base = Url::parse(base_url)?;
+    if next.scheme() == base.scheme() && next.host_str() == base.host_str() && next.port() == base.port() {
+        Ok(())
     } else {
-        let error_text = response.text().await?;
-        println!("Error fetching contracts: Status code {}, Response: {}", status, error_text);
-        Ok(Vec::new())
+        Err(format!(
+            "Refusing to follow next_url on unexpected host {:?} (expected {:?})",
+            next.host_str().unwrap_or(""),
+            base.host_str().unwrap_or("")
+        )
+        .into())
     }
 }
 
+fn matches_any_strike(contract: &Value, strikes: &[f64]) -> bool {
+    contract["strike_price"]
+        .as_f64()
+        .map(|strike| strikes.iter().any(|s| (s - strike).abs() < STRIKE_EPSILON))
+        .unwrap_or(false)
+}
+
+fn is_strike_multiple(contract: &Value, increment: f64) -> bool {
+    contract["strike_price"]
+        .as_f64()
+        .map(|strike| {
+            let steps = strike / increment;
+            (steps - steps.round()).abs() * increment < STRIKE_EPSILON
+        })
+        .unwrap_or(false)
+}
+
+fn snapshot_url(api_host: &str, underlying_asset: &str, option_ticker: &str) -> String {
+    format!(
+        "{}/v3/snapshot/options/{}/{}",
+        api_host,
+        underlying_asset,
+        encode(option_ticker)
+    )
+}
 
 async fn get_contract_details(
     client: &Client,
     api_key: &str,
     underlying_asset: &str,
     option_ticker: &str,
+    api_host: &str,
+    retry: RetryPolicy<'_>,
+) -> Result<Value, Error> {
+    let base_url = snapshot_url(api_host, underlying_asset, option_ticker);
+
+    let response = send_with_retry(client.get(&base_url).query(&[("apiKey", api_key)]), retry).await?;
+
+    let status = response.status(); // Capture the status code before consuming the response
+
+    if status.is_success() {
+        let data: Value = response.json().await?;
+        // Polygon occasionally answers 200 with an error object in place of
+        // the snapshot; surface it rather than formatting an all-N/A row.
+        let error = &data["results"]["error"];
+        if !error.is_null() {
+            let message = error
+                .as_str()
+                .or_else(|| error["message"].as_str())
+                .map(|m| m.to_string())
+                .unwrap_or_else(|| error.to_string());
+            return Err(format!("snapshot returned an error: {}", message).into());
+        }
+        Ok(data["results"].clone())
+    } else {
+        let error_text = response.text().await?;
+        error!(ticker = %option_ticker, %status, response = %error_text, "Error fetching details");
+        // Polygon error bodies are JSON with a `message`; fall back to the
+        // raw text.
+        let message = serde_json::from_str::<Value>(&error_text)
+            .ok()
+            .and_then(|body| body["message"].as_str().map(|m| m.to_string()))
+            .unwrap_or(error_text);
+        Err(Box::new(SnapshotError { status: Some(status.as_u16()), message }))
+    }
+}
+
+// A snapshot Polygon answered with an error status, kept apart from
+// transport errors so the response can report the status code.
+#[derive(Debug)]
+struct SnapshotError {
+    status: Option<u16>,
+    message: String,
+}
+
+impl std::fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.status {
+            Some(status) => write!(f, "snapshot request failed with status {}: {}", status, self.message),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+impl std::error::Error for SnapshotError {}
+
+// Latest trade price for the underlying, used as a fresher spot than the
+// price embedded in each option snapshot.
+async fn get_underlying_price(
+    client: &Client,
+    api_key: &str,
+    api_host: &str,
+    ticker_symbol: &str,
+    retry: RetryPolicy<'_>,
+) -> Result<Option<(f64, Option<i64>)>, Error> {
+    let base_url = format!(
+        "{}/v2/last/trade/{}",
+        api_host,
+        encode(ticker_symbol)
+    );
+
+    let response = send_with_retry(client.get(&base_url).query(&[("apiKey", api_key)]), retry).await?;
+
+    let status = response.status(); // Capture the status code before consuming the response
+
+    if status.is_success() {
+        let data: Value = response.json().await?;
+        // The trade price with its SIP timestamp (ns), if Polygon sent one.
+        Ok(data["results"]["p"].as_f64().map(|price| (price, data["results"]["t"].as_i64())))
+    } else {
+        let error_text = response.text().await?;
+        error!(ticker = %ticker_symbol, %status, response = %error_text, "Error fetching underlying price");
+        Ok(None)
+    }
+}
+
+// Daily aggregates for one option contract, compacted to
+// `[date, open, high, low, close, volume]` rows to keep the payload small.
+async fn get_contract_history(
+    client: &Client,
+    api_key: &str,
+    api_host: &str,
+    option_ticker: &str,
+    from: NaiveDate,
+    to: NaiveDate,
+    retry: RetryPolicy<'_>,
 ) -> Result<Value, Error> {
-    let encoded_option_ticker = encode(option_ticker);
     let base_url = format!(
-        "https://api.polygon.io/v3/snapshot/options/{}/{}",
-        underlying_asset, encoded_option_ticker
+        "{}/v2/aggs/ticker/{}/range/1/day/{}/{}",
+        api_host,
+        encode(option_ticker),
+        from.format("%Y-%m-%d"),
+        to.format("%Y-%m-%d")
     );
 
-    let response = client
+    let request = client
         .get(&base_url)
-        .query(&[("apiKey", api_key)])
-        .send()
-        .await?;
+        .query(&[("apiKey", api_key), ("adjusted", "true"), ("sort", "asc")]);
+    let response = send_with_retry(request, retry).await?;
 
     let status = response.status(); // Capture the status code before consuming the response
 
     if status.is_success() {
         let data: Value = response.json().await?;
-        Ok(data["results"].clone())
+        let rows: Vec<Value> = data["results"]
+            .as_array()
+            .unwrap_or(&vec![])
+            .iter()
+            .map(|bar| {
+                let date = bar["t"]
+                    .as_i64()
+                    .and_then(DateTime::<Utc>::from_timestamp_millis)
+                    .map(|t| t.format("%Y-%m-%d").to_string());
+                json!([date, bar["o"], bar["h"], bar["l"], bar["c"], bar["v"]])
+            })
+            .collect();
+        Ok(json!(rows))
     } else {
         let error_text = response.text().await?;
-        println!(
-            "Error fetching details for {}: Status code {}, Response: {}",
-            option_ticker, status, error_text
-        );
+        error!(ticker = %option_ticker, %status, response = %error_text, "Error fetching history");
         Ok(Value::Null)
     }
 }
 
-async fn function_handler(event: LambdaEvent<Value>) -> Result<Response, Error> {
-    println!("Received event: {:?}", event);
-
-    let (payload, request_id) = if let Some(query_params) = event.payload.get("queryStringParameters") {
-        // Parameters are in query string
-        let payload = extract_parameters_from_value(query_params);
-        let request_id = event
-            .payload
-            .get("requestContext")
-            .and_then(|rc| rc.get("requestId"))
-            .and_then(|id| id.as_str())
-            .unwrap_or(&event.context.request_id)
-            .to_string();
-        (payload, request_id)
-    } else if let Some(headers) = event.payload.get("headers") {
-        // Parameters are in headers
-        let payload = extract_parameters_from_value(headers);
-        let request_id = event
-            .payload
-            .get("requestContext")
-            .and_then(|rc| rc.get("requestId"))
-            .and_then(|id| id.as_str())
-            .unwrap_or(&event.context.request_id)
-            .to_string();
-        (payload, request_id)
-    } else if let Some(body) = event.payload.get("body") {
-        // Parameters are in body
-        let body_str = body.as_str().unwrap_or("");
-        let payload: Payload = serde_json::from_str(body_str).unwrap_or_default();
-        let request_id = event
-            .payload
-            .get("requestContext")
-            .and_then(|rc| rc.get("requestId"))
-            .and_then(|id| id.as_str())
-            .unwrap_or(&event.context.request_id)
-            .to_string();
-        (payload, request_id)
-    } else {
-        // Direct invocation or test event
-        let payload: Payload = serde_json::from_value(event.payload.clone()).unwrap_or_default();
-        (payload, event.context.request_id.clone())
-    };
+async fn fetch_with_permit(
+    client: &Client,
+    api_key: &str,
+    underlying_asset: &str,
+    option_ticker: &str,
+    api_host: &str,
+    semaphore: &Semaphore,
+    retry: RetryPolicy<'_>,
+) -> Result<Value, Error> {
+    let _permit = semaphore.acquire().await?;
+    get_contract_details(client, api_key, underlying_asset, option_ticker, api_host, retry).await
+}
 
-    // Extract parameters
-    let ticker_symbol = payload.ticker_symbol.unwrap_or_else(|| "AAPL".to_string());
-    let api_key = payload.api_key.unwrap_or_else(|| "YOUR_API_KEY".to_string());
-    let limit = payload.limit.unwrap_or("10".to_string());
-    let days_forward = payload.days_forward.unwrap_or("30".to_string());
-    let contract_type = payload.contract_type.unwrap_or("call".to_string());
-
-    println!("Using parameters:");
-    println!("Ticker Symbol: {}", ticker_symbol);
-    println!("API Key: {}", api_key);
-    println!("Limit: {}", limit);
-    println!("Days Forward: {}", days_forward);
-    println!("Contract Type: {}", contract_type);
-
-    let client = Client::new();
-    let contract_tickers = get_relevant_option_contracts(
-        &client,
-        &api_key,
-        &ticker_symbol,
-        &limit,
-        &days_forward,
-        &contract_type,
-    )
-    .await?;
+// Hard cap on snapshot requests per invocation, independent of `limit`, so a
+// misconfigured caller can't run up the Polygon bill. Read from the
+// MAX_DETAIL_FETCHES env var.
+fn max_detail_fetches() -> usize {
+    env::var("MAX_DETAIL_FETCHES")
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(DEFAULT_MAX_DETAIL_FETCHES)
+}
 
-    println!("Retrieved contract tickers: {:?}", contract_tickers);
+const DEFAULT_MAX_DETAIL_FETCHES: usize = 1000;
 
-    // Fetch details concurrently for better performance
-    let fetches = contract_tickers.iter().map(|ticker| {
-        get_contract_details(&client, &api_key, &ticker_symbol, ticker)
-    });
+const DEFAULT_MAX_CONCURRENCY: usize = 20;
 
-    let contracts_data = join_all(fetches).await;
+// A positive `max_concurrency`, or the default.
+fn parse_max_concurrency(max_concurrency: Option<&str>) -> usize {
+    max_concurrency
+        .and_then(|c| c.parse().ok())
+        .filter(|&c| c > 0)
+        .unwrap_or(DEFAULT_MAX_CONCURRENCY)
+}
 
-    // Process and format the data
-    let formatted_contracts: Vec<Value> = contracts_data
-        .into_iter()
-        .filter_map(|result| match result {
-            Ok(contract) => {
-                if contract.is_null() {
-                    println!("Contract data is null.");
-                    None
-                } else {
-                    let contract_type = contract["details"]["contract_type"]
-                        .as_str()
-                        .unwrap_or("N/A");
-                    let expiration_date = contract["details"]["expiration_date"]
-                        .as_str()
-                        .unwrap_or("N/A");
-                    let strike_price = contract["details"]["strike_price"]
-                        .as_f64()
-                        .map(|p| p.to_string())
-                        .unwrap_or("N/A".to_string());
-                    let implied_volatility = contract["implied_volatility"]
-                        .as_f64()
-                        .map(|v| format!("{:.2}%", v * 100.0))
-                        .unwrap_or("N/A".to_string());
-                    let open_interest = contract["open_interest"]
-                        .as_u64()
-                        .map(|v| v.to_string())
-                        .unwrap_or("N/A".to_string());
-                    let premium = contract["last_quote"]["midpoint"]
-                        .as_f64()
-                        .map(|p| format!("{:.2}", p))
-                        .unwrap_or("N/A".to_string());
-                    let ticker = contract["details"]["ticker"]
-                        .as_str()
-                        .unwrap_or("N/A");
-
-                    Some(json!({
-                        "contract_type": contract_type,
-                        "expiration_date": expiration_date,
-                        "implied_volatility": implied_volatility,
-                        "open_interest": open_interest,
-                        "premium": premium,
-                        "strike_price": strike_price,
-                        "ticker": ticker
-                    }))
-                }
-            }
-            Err(e) => {
-                println!("Error fetching contract details: {}", e);
-                None
-            }
-        })
-        .collect();
+// What one invocation may spend on Polygon, shared by every underlying it
+// queries: the semaphore bounds the requests in flight at once, and
+// `remaining_fetches` the snapshot requests made in total, so a watchlist or
+// prewarm stays within `max_concurrency` and MAX_DETAIL_FETCHES however many
+// tickers it lists.
+struct FetchBudget {
+    semaphore: Semaphore,
+    max_fetches: usize,
+    remaining_fetches: AtomicUsize,
+}
 
-    println!("Formatted contracts: {:?}", formatted_contracts);
+impl FetchBudget {
+    fn new(max_concurrency: usize, max_fetches: usize) -> Self {
+        FetchBudget {
+            semaphore: Semaphore::new(max_concurrency),
+            max_fetches,
+            remaining_fetches: AtomicUsize::new(max_fetches),
+        }
+    }
 
-    let resp = Response {
-        req_id: request_id,
-        response: serde_json::to_string(&json!({ "option_contracts": formatted_contracts }))?,
+    // Claims up to `wanted` snapshot fetches, returning how many were granted.
+    fn take_fetches(&self, wanted: usize) -> usize {
+        let previous = self
+            .remaining_fetches
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |remaining| {
+                Some(remaining.saturating_sub(wanted))
+            })
+            .unwrap_or_default();
+        previous.min(wanted)
+    }
+}
+
+// How snapshot requests are paced, each carrying the concurrency limit.
+#[derive(Clone, Copy, Debug)]
+enum FetchPacing {
+    // Keep up to the limit in flight from the start.
+    Steady(usize),
+    // Start with one request and double the batch after each fully
+    // successful batch, up to the limit.
+    Ramp(usize),
+}
+
+// Fetches snapshots for (underlying, option ticker) pairs, returning results
+// in job order. Futures are only created as earlier ones finish, so a large
+// `limit` never builds more than the concurrency limit's worth at once; on top
+// of that every request in the invocation draws from the same semaphore, so
+// the number in flight stays bounded by `max_concurrency` no matter how many
+// underlyings are involved.
+//
+// Ramping avoids a burst of 429s against a cold rate limit.
+async fn fetch_all_contract_details(
+    client: &Client,
+    api_key: &str,
+    jobs: &[(&str, &str)],
+    api_host: &str,
+    semaphore: &Semaphore,
+    pacing: FetchPacing,
+    retry: RetryPolicy<'_>,
+) -> Vec<Result<Value, Error>> {
+    let max_batch = match pacing {
+        FetchPacing::Ramp(max_batch) => max_batch,
+        FetchPacing::Steady(concurrency) => {
+            return stream::iter(jobs)
+                .map(|(underlying_asset, option_ticker)| {
+                    fetch_with_permit(client, api_key, underlying_asset, option_ticker, api_host, semaphore, retry)
+                })
+                .buffered(concurrency.max(1))
+                .collect()
+                .await
+        }
     };
 
-    Ok(resp)
+    let mut results = Vec::with_capacity(jobs.len());
+    let mut batch_size = 1;
+    let mut remaining = jobs;
+    while !remaining.is_empty() {
+        let (batch, rest) = remaining.split_at(batch_size.min(remaining.len()));
+        let batch_results = join_all(batch.iter().map(|(underlying_asset, option_ticker)| {
+            fetch_with_permit(client, api_key, underlying_asset, option_ticker, api_host, semaphore, retry)
+        }))
+        .await;
+        let all_ok = batch_results
+            .iter()
+            .all(|result| matches!(result, Ok(contract) if !contract.is_null()));
+        results.extend(batch_results);
+        if all_ok {
+            batch_size = (batch_size * 2).min(max_batch);
+        }
+        remaining = rest;
+    }
+    results
+}
+
+// A process-lifetime map whose entries expire by age. Lookups pass the age
+// they accept. Once `max_entries` is reached, an insert under a new key
+// evicts the oldest entry. Concurrent queries (a watchlist's fan-out,
+// overlapping invocations in tests) share it through the mutex, which is
+// never held across an await. Each write leaves the map consistent, so a
+// lock poisoned by a panicking holder is simply recovered.
+struct TtlCache<V> {
+    entries: Mutex<HashMap<String, (Instant, V)>>,
+    max_entries: usize,
 }
 
-fn extract_parameters_from_value(value: &Value) -> Payload {
-    Payload {
-        ticker_symbol: value.get("ticker_symbol").and_then(|v| v.as_str()).map(|s| s.to_string()),
-        api_key: value.get("api_key").and_then(|v| v.as_str()).map(|s| s.to_string()),
-        limit: value.get("limit").and_then(|v| v.as_str()).map(|s| s.to_string()),
-        days_forward: value.get("days_forward").and_then(|v| v.as_str()).map(|s| s.to_string()),
-        contract_type: value.get("contract_type").and_then(|v| v.as_str()).map(|s| s.to_string()),
+impl<V: Clone> TtlCache<V> {
+    fn new(max_entries: usize) -> Self {
+        TtlCache {
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+        }
+    }
+
+    // The entry's age and value, if it is no older than `max_age`.
+    fn get(&self, key: &str, max_age: StdDuration) -> Option<(StdDuration, V)> {
+        let entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let (stored_at, value) = entries.get(key)?;
+        let age = stored_at.elapsed();
+        (age <= max_age).then(|| (age, value.clone()))
+    }
+
+    fn insert(&self, key: String, value: V) {
+        let mut entries = self.entries.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if entries.len() >= self.max_entries && !entries.contains_key(&key) {
+            let oldest = entries
+                .iter()
+                .min_by_key(|(_, (stored_at, _))| *stored_at)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                entries.remove(&oldest);
+            }
+        }
+        entries.insert(key, (Instant::now(), value));
     }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Error> {
-    run(service_fn(function_handler)).await
-}
\ No newline at end of file
+// Response bodies, keyed by the request parameters. Entries are only served
+// to callers that opt in with `max_stale_secs`, and only while younger than
+// that.
+static RESPONSE_CACHE: OnceLock<TtlCache<Value>> = OnceLock::new();
+
+const RESPONSE_CACHE_MAX_ENTRIES: usize = 100;
+
+fn response_cache() -> &'static TtlCache<Value> {
+    RESPONSE_CACHE.get_or_init(|| TtlCache::new(RESPONSE_CACHE_MAX_ENTRIES))
+}
+
+// HTTP header names are case-insensitive, and API Gateway may or may not
+// lowercase them.
+fn header_correlation_id(event_payload: &Value) -> Option<String> {
+    event_payload
+        .get("headers")?
+        .as_object()?
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("x-correlation-id"))
+        .and_then(|(_, value)| value.as_str())
+        .map(|s| s.to_string())
+}
+
+// The key covers every parameter that shapes the response, plus a hash of
+// the resolved API key: a body fetched with one key must not be served to a
+// caller using another, whose plan may not cover the same data. The key
+// itself is never stored, and delivery-only settings are left out so they
+// don't split the cache.
+fn response_cache_key(payload: &Payload, api_key: &str) -> String {
+    let mut key_payload = payload.clone();
+    key_payload.api_key = None;
+    key_payload.max_stale_secs = None;
+    key_payload.bypass_cache = None;
+    key_payload.callback_url = None;
+    key_payload.correlation_id = None;
+    let mut hasher = DefaultHasher::new();
+    api_key.hash(&mut hasher);
+    format!(
+        "{:016x}:{}",
+        hasher.finish(),
+        serde_json::to_string(&key_payload).unwrap_or_default()
+    )
+}
+
+fn response_cache_lookup(key: &str, max_stale_secs: u64) -> Option<(StdDuration, Value)> {
+    response_cache().get(key, StdDuration::from_secs(max_stale_secs))
+}
+
+fn response_cache_store(key: String, body: &Value) {
+    response_cache().insert(key, body.clone());
+}
+
+// Contract listings. The contracts in an expiration window barely change
+// intraday, so warm containers reuse a listing for LISTING_CACHE_TTL_SECS
+// instead of re-reading the reference endpoint; the fast-moving snapshots
+// are always fetched fresh.
+static LISTING_CACHE: OnceLock<TtlCache<ContractListing>> = OnceLock::new();
+
+const DEFAULT_LISTING_CACHE_TTL_SECS: usize = 300;
+// A listing is far larger than a response body, and one invocation can
+// cache a listing per watchlist ticker.
+const LISTING_CACHE_MAX_ENTRIES: usize = 50;
+
+fn listing_cache() -> &'static TtlCache<ContractListing> {
+    LISTING_CACHE.get_or_init(|| TtlCache::new(LISTING_CACHE_MAX_ENTRIES))
+}
+
+// Every ContractQuery field that shapes the listing, plus the host it came
+// from. The API key is left out.
+fn listing_cache_key(api_host: &str, query: &ContractQuery<'_>) -> String {
+    json!({
+        "api_host": api_host,
+        "ticker_symbol": query.ticker_symbol,
+        "limit": query.limit,
+        "window": query.window.to_json(),
+        "contract_type": query.contract_type,
+        "strikes": query.strikes,
+        "strike_range": [query.strike_range.0, query.strike_range.1],
+        "expired": query.expired,
+        "strike_increment": query.strike_increment,
+        "expiration_weekday": query.expiration_weekday.map(|weekday| weekday.to_string()),
+        "extra_params": query.extra_params,
+    })
+    .to_string()
+}
+
+fn listing_cache_lookup(key: &str, ttl: StdDuration) -> Option<ContractListing> {
+    listing_cache().get(key, ttl).map(|(_, listing)| listing)
+}
+
+fn listing_cache_store(key: String, listing: &ContractListing) {
+    listing_cache().insert(key, listing.clone());
+}
+
+fn is_empty_payload(payload: &Payload) -> bool {
+    match serde_json::to_value(payload) {
+        Ok(Value::Object(fields)) => fields.values().all(Value::is_null),
+        _ => false,
+    }
+}
+
+// Short description of the main parameters, returned for parameterless
+// invocations when DEMO_MODE is set. The README has the full list.
+fn usage() -> Value {
+    json!({
+        "usage": {
+            "description": "Returns option contracts for an underlying from Polygon.io",
+            "parameters": {
+                "ticker_symbol": "Underlying ticker, e.g. \"AAPL\"",
+                "api_key": "Polygon.io API key",
+                "limit": "Maximum number of contracts to retrieve (default: 10)",
+                "days_forward": "Days ahead to look for expirations (default: 30)",
+                "contract_type": "\"call\", \"put\" or \"both\" (default: call)",
+                "strikes": "Exact strikes to return, e.g. [150, 155]",
+                "sort_by": "Field to sort the results by",
+                "sort_order": "\"asc\" or \"desc\"",
+                "output_format": "\"compact\" for header/data arrays or \"map_by_ticker\""
+            },
+            "example": {
+                "ticker_symbol": "AAPL",
+                "api_key": "YOUR_POLYGON_API_KEY",
+                "limit": "20",
+                "days_forward": "60",
+                "contract_type": "put"
+            }
+        }
+    })
+}
+
+const DEFAULT_MAX_REQUEST_BYTES: usize = 64 * 1024;
+const DEFAULT_MAX_EXTRA_PARAMS: usize = 20;
+
+fn env_limit(name: &str, default: usize) -> usize {
+    env::var(name)
+        .ok()
+        .and_then(|v| v.parse().ok())
+        .unwrap_or(default)
+}
+
+// Rejects the request without calling Polygon. The body mirrors an HTTP
+// error so callers can branch on `status_code`.
+fn error_response(request_id: String, status_code: u16, message: String) -> Result<Response, Error> {
+    info!(status_code, %message, "Rejecting request");
+    Ok(Response {
+        req_id: request_id,
+        response: serde_json::to_string(&json!({
+            "error": { "status_code": status_code, "message": message }
+        }))?,
+    })
+}
+
+// A 400 that names the offending parameter in `field`.
+fn invalid_field_response(request_id: String, field: &str, message: String) -> Result<Response, Error> {
+    info!(status_code = 400, field, %message, "Rejecting request");
+    Ok(Response {
+        req_id: request_id,
+        response: serde_json::to_string(&json!({
+            "error": { "status_code": 400, "message": message, "field": field }
+        }))?,
+    })
+}
+
+// Furthest out `days_forward` may reach; listed LEAPS run about three years.
+const MAX_DAYS_FORWARD: i64 = 1095;
+
+// Furthest back `days_back` may reach, to the same depth.
+const MAX_DAYS_BACK: i64 = 1095;
+
+// Checks the core parameters up front so client bugs surface as a 400 naming
+// the field instead of being papered over with defaults. Omitted optional
+// parameters still take their defaults; only the ticker is required.
+fn validate_core_parameters(payload: &Payload) -> Result<(), (&'static str, String)> {
+    match payload.ticker_symbol.as_deref().map(str::trim) {
+        None | Some("") => return Err(("ticker_symbol", "ticker_symbol is required".to_string())),
+        Some(ticker) if !is_valid_ticker(ticker) => {
+            return Err((
+                "ticker_symbol",
+                format!("ticker_symbol {:?} must be 1-10 letters, digits or '.'", ticker),
+            ));
+        }
+        Some(_) => {}
+    }
+    match &payload.limit {
+        Some(NumericParam::Value(limit)) if *limit == 0 => {
+            return Err(("limit", "limit 0 is not a positive integer".to_string()));
+        }
+        Some(NumericParam::Invalid(limit)) => {
+            return Err(("limit", format!("limit {:?} is not a positive integer", limit)));
+        }
+        _ => {}
+    }
+    match &payload.top_n {
+        Some(NumericParam::Value(top_n)) if *top_n == 0 => {
+            return Err(("top_n", "top_n 0 is not a positive integer".to_string()));
+        }
+        Some(NumericParam::Invalid(top_n)) => {
+            return Err(("top_n", format!("top_n {:?} is not a positive integer", top_n)));
+        }
+        _ => {}
+    }
+    match &payload.days_forward {
+        Some(NumericParam::Value(days)) if (0..=MAX_DAYS_FORWARD).contains(days) => {}
+        Some(days_forward) => {
+            let days_forward = match days_forward {
+                NumericParam::Value(days) => days.to_string(),
+                NumericParam::Invalid(raw) => format!("{:?}", raw),
+            };
+            return Err((
+                "days_forward",
+                format!("days_forward {} must be an integer from 0 to {}", days_forward, MAX_DAYS_FORWARD),
+            ));
+        }
+        None => {}
+    }
+    for (field, bound) in [
+        ("strike_price_gte", &payload.strike_price_gte),
+        ("strike_price_lte", &payload.strike_price_lte),
+    ] {
+        if let Some(bound) = bound {
+            if !bound.trim().parse::<f64>().is_ok_and(|k| k.is_finite() && k >= 0.0) {
+                return Err((field, format!("{} {:?} is not a valid strike price", field, bound)));
+            }
+        }
+    }
+    if let Some(min_dte) = &payload.min_dte {
+        if !min_dte
+            .trim()
+            .parse::<i64>()
+            .is_ok_and(|days| (0..=MAX_DAYS_FORWARD).contains(&days))
+        {
+            return Err((
+                "min_dte",
+                format!("min_dte {:?} must be an integer from 0 to {}", min_dte, MAX_DAYS_FORWARD),
+            ));
+        }
+    }
+    if let Some(days_back) = &payload.days_back {
+        if !days_back
+            .trim()
+            .parse::<i64>()
+            .is_ok_and(|days| (0..=MAX_DAYS_BACK).contains(&days))
+        {
+            return Err((
+                "days_back",
+                format!("days_back {:?} must be an integer from 0 to {}", days_back, MAX_DAYS_BACK),
+            ));
+        }
+    }
+    if let Some(history_days) = &payload.history_days {
+        if !history_days
+            .trim()
+            .parse::<i64>()
+            .is_ok_and(|days| (1..=MAX_DAYS_BACK).contains(&days))
+        {
+            return Err((
+                "history_days",
+                format!("history_days {:?} must be an integer from 1 to {}", history_days, MAX_DAYS_BACK),
+            ));
+        }
+    }
+    if let Some(increment) = &payload.strike_increment {
+        if !increment.trim().parse::<f64>().is_ok_and(|k| k.is_finite() && k > 0.0) {
+            return Err((
+                "strike_increment",
+                format!("strike_increment {:?} is not a positive number", increment),
+            ));
+        }
+    }
+    for (field, bound) in [("min_gamma", &payload.min_gamma), ("max_gamma", &payload.max_gamma)] {
+        match bound {
+            Some(NumericParam::Value(gamma)) if gamma.is_finite() => {}
+            Some(bound) => {
+                let bound = match bound {
+                    NumericParam::Value(gamma) => gamma.to_string(),
+                    NumericParam::Invalid(raw) => format!("{:?}", raw),
+                };
+                return Err((field, format!("{} {} is not a number", field, bound)));
+            }
+            None => {}
+        }
+    }
+    if let (Some(NumericParam::Value(min)), Some(NumericParam::Value(max))) = (&payload.min_gamma, &payload.max_gamma) {
+        if min > max {
+            return Err(("min_gamma", format!("min_gamma {} is above max_gamma {}", min, max)));
+        }
+    }
+    match &payload.max_cost {
+        Some(NumericParam::Value(budget)) if budget.is_finite() && *budget >= 0.0 => {}
+        Some(max_cost) => {
+            let max_cost = match max_cost {
+                NumericParam::Value(budget) => budget.to_string(),
+                NumericParam::Invalid(raw) => format!("{:?}", raw),
+            };
+            return Err(("max_cost", format!("max_cost {} is not a non-negative dollar amount", max_cost)));
+        }
+        None => {}
+    }
+    if let Some(offsets) = &payload.strike_offsets {
+        if let Some(offset) = offsets.iter().find(|offset| !offset.is_finite() || offset.fract() != 0.0) {
+            return Err((
+                "strike_offsets",
+                format!("strike_offsets must be whole numbers of strikes, got {}", offset),
+            ));
+        }
+    }
+    if let Some(weekday) = &payload.expiration_weekday {
+        if parse_weekday(weekday).is_none() {
+            return Err((
+                "expiration_weekday",
+                format!(
+                    "expiration_weekday {:?} must be a weekday name or a number from 0 (Monday) to 6 (Sunday)",
+                    weekday
+                ),
+            ));
+        }
+    }
+    if let Some(sort_by) = &payload.sort_by {
+        if !SORT_FIELDS.contains(&sort_by.as_str()) {
+            return Err(("sort_by", format!("sort_by {:?} is not a sortable field", sort_by)));
+        }
+    }
+    if let Some(sort_order) = &payload.sort_order {
+        if !matches!(sort_order.trim().to_lowercase().as_str(), "asc" | "desc") {
+            return Err(("sort_order", format!("sort_order {:?} must be \"asc\" or \"desc\"", sort_order)));
+        }
+    }
+    if let Some(contract_type) = &payload.contract_type {
+        if !matches!(contract_type.trim().to_lowercase().as_str(), "call" | "put" | "both" | "") {
+            return Err((
+                "contract_type",
+                format!("contract_type {:?} must be \"call\", \"put\" or \"both\"", contract_type),
+            ));
+        }
+    }
+    Ok(())
+}
+
+// Underlying tickers are short runs of letters and digits, with '.' for
+// share classes such as BRK.B.
+fn is_valid_ticker(ticker: &str) -> bool {
+    (1..=10).contains(&ticker.len()) && ticker.chars().all(|c| c.is_ascii_alphanumeric() || c == '.')
+}
+
+const CALLBACK_MAX_ATTEMPTS: u32 = 3;
+
+// POSTs the finished response to a caller-supplied URL, retrying with
+// exponential backoff. Every attempt carries the same Idempotency-Key (the
+// request id) so a receiver can drop the duplicates a retry after a lost
+// acknowledgement would otherwise cause. The API key is scrubbed from the
+// body first. Failures are logged only; the invocation still returns its
+// response normally.
+async fn post_callback(client: &Client, callback_url: &str, resp: &Response, api_key: &str) {
+    let body = match serde_json::to_string(resp) {
+        Ok(body) => redact_api_key(&body, api_key),
+        Err(e) => {
+            error!(error = %e, "Error serializing callback payload");
+            return;
+        }
+    };
+
+    for attempt in 1..=CALLBACK_MAX_ATTEMPTS {
+        let result = client
+            .post(callback_url)
+            .header("Content-Type", "application/json")
+            .header("Idempotency-Key", &resp.req_id)
+            .body(body.clone())
+            .send()
+            .await;
+        match result {
+            Ok(response) if response.status().is_success() => {
+                info!(callback_url, attempt, "Callback delivered");
+                return;
+            }
+            Ok(response) => warn!(callback_url, attempt, status = %response.status(), "Callback failed"),
+            Err(e) => warn!(callback_url, attempt, error = %e, "Callback failed"),
+        }
+        if attempt < CALLBACK_MAX_ATTEMPTS {
+            sleep(StdDuration::from_millis(200 * 2u64.pow(attempt - 1))).await;
+        }
+    }
+}
+
+static HTTP_CLIENT: OnceLock<Client> = OnceLock::new();
+
+const DEFAULT_HTTP_TIMEOUT_MS: usize = 10_000;
+const DEFAULT_HTTP_CONNECT_TIMEOUT_MS: usize = 3_000;
+
+// One client per container, so warm invocations reuse its connection pool.
+// Every request gets a deadline (HTTP_TIMEOUT_MS, HTTP_CONNECT_TIMEOUT_MS) so
+// a hung connection fails that request instead of holding the Lambda until
+// its function timeout. Per-request timeouts such as `page_timeout_ms` take
+// precedence.
+fn http_client() -> Client {
+    HTTP_CLIENT
+        .get_or_init(|| {
+            let timeout = env_limit("HTTP_TIMEOUT_MS", DEFAULT_HTTP_TIMEOUT_MS);
+            let connect_timeout = env_limit("HTTP_CONNECT_TIMEOUT_MS", DEFAULT_HTTP_CONNECT_TIMEOUT_MS);
+            Client::builder()
+                .timeout(StdDuration::from_millis(timeout as u64))
+                .connect_timeout(StdDuration::from_millis(connect_timeout as u64))
+                .build()
+                .unwrap_or_else(|e| {
+                    error!(error = %e, "Error building HTTP client, using defaults");
+                    Client::new()
+                })
+        })
+        .clone()
+}
+
+static POLYGON_API_KEY: OnceLock<Option<String>> = OnceLock::new();
+
+// The API key configured on the deployment, read once per container.
+fn env_api_key() -> Option<&'static str> {
+    POLYGON_API_KEY
+        .get_or_init(|| env::var("POLYGON_API_KEY").ok().filter(|key| !key.is_empty()))
+        .as_deref()
+}
+
+// The deployment's POLYGON_API_KEY wins; a key in the request is only
+// honored when there is none, or when ALLOW_PAYLOAD_API_KEY opts in. None
+// when neither is set, which callers reject before calling Polygon.
+fn resolve_api_key(payload_key: Option<String>, warnings: &mut Vec<String>) -> Option<String> {
+    match (env_api_key(), payload_key.filter(|key| !key.trim().is_empty())) {
+        (Some(_), Some(payload_key)) if env_flag("ALLOW_PAYLOAD_API_KEY") => Some(payload_key),
+        (Some(env_key), Some(_)) => {
+            warnings.push(
+                "Ignored api_key from the request: the deployment's POLYGON_API_KEY is used".to_string(),
+            );
+            Some(env_key.to_string())
+        }
+        (Some(env_key), None) => Some(env_key.to_string()),
+        (None, Some(payload_key)) => Some(payload_key),
+        (None, None) => None,
+    }
+}
+
+const MISSING_API_KEY_MESSAGE: &str =
+    "No Polygon API key is configured: set POLYGON_API_KEY on the function or send api_key";
+
+fn env_flag(name: &str) -> bool {
+    env::var(name).is_ok_and(|v| v == "true" || v == "1")
+}
+
+fn redact_api_key(text: &str, api_key: &str) -> String {
+    if api_key.is_empty() {
+        text.to_string()
+    } else {
+        text.replace(api_key, "REDACTED")
+    }
+}
+
+// API Gateway and Function URL events carry the request in one of these
+// keys; anything else is a direct invocation.
+fn is_proxy_event(payload: &Value) -> bool {
+    ["queryStringParameters", "headers", "body"]
+        .iter()
+        .any(|key| payload.get(key).is_some())
+}
+
+// Proxy events get a proxy integration response: the status code comes from
+// the body's `error` (200 without one, 500 when the handler itself fails),
+// with CORS and content-type headers and the body as a string. Direct
+// invocations keep getting the plain `Response`.
+async fn handle_event(event: LambdaEvent<Value>) -> Result<Value, Error> {
+    let proxy = is_proxy_event(&event.payload);
+    let context_request_id = event.context.request_id.clone();
+    // Every line logged for this invocation carries its request id, and the
+    // client's correlation id once the payload has been read.
+    let span = info_span!("invocation", request_id = %context_request_id, correlation_id = field::Empty);
+    let result = function_handler(event).instrument(span).await;
+    if !proxy {
+        return Ok(serde_json::to_value(result?)?);
+    }
+
+    let (status_code, req_id, body) = match result {
+        Ok(resp) => {
+            let status_code = serde_json::from_str::<Value>(&resp.response)
+                .ok()
+                .and_then(|body| body["error"]["status_code"].as_u64())
+                .unwrap_or(200);
+            (status_code, resp.req_id, resp.response)
+        }
+        Err(e) => {
+            // Logged only: the error text can carry upstream details that
+            // shouldn't reach a browser.
+            let message = redact_api_key(&e.to_string(), env_api_key().unwrap_or_default());
+            error!(error = %message, "Error handling request");
+            let body = json!({ "error": { "status_code": 500, "message": "Internal error" } });
+            (500, context_request_id, serde_json::to_string(&body)?)
+        }
+    };
+    Ok(json!({
+        "statusCode": status_code,
+        "headers": {
+            "Content-Type": "application/json",
+            "Access-Control-Allow-Origin": env::var("CORS_ALLOW_ORIGIN").unwrap_or("*".to_string()),
+            "X-Request-Id": req_id,
+        },
+        "body": body,
+        "isBase64Encoded": false,
+    }))
+}
+
+async fn function_handler(event: LambdaEvent<Value>) -> Result<Response, Error> {
+    let request_size = serde_json::to_string(&event.payload)?.len();
+    let max_request_bytes = env_limit("MAX_REQUEST_BYTES", DEFAULT_MAX_REQUEST_BYTES);
+    if request_size > max_request_bytes {
+        return error_response(
+            event.context.request_id.clone(),
+            400,
+            format!(
+                "Request is {} bytes, over the {} byte limit",
+                request_size, max_request_bytes
+            ),
+        );
+    }
+
+    if let Some(tickers) = scheduled_prewarm_tickers(&event.payload) {
+        return handle_prewarm(tickers, &event.payload["detail"], event.context.request_id.clone()).await;
+    }
+
+    let (payload, request_id) = if is_proxy_event(&event.payload) {
+        let request_id = event.payload["requestContext"]["requestId"]
+            .as_str()
+            .unwrap_or(&event.context.request_id)
+            .to_string();
+        (proxy_parameters(&event.payload), request_id)
+    } else {
+        // Direct invocation or test event
+        let payload: Payload = serde_json::from_value(event.payload.clone()).unwrap_or_default();
+        (payload, event.context.request_id.clone())
+    };
+
+    // Logged once the payload is parsed, so a key sent in it can be scrubbed.
+    debug!(
+        event = %redact_api_key(&format!("{:?}", event), payload.api_key.as_deref().unwrap_or("")),
+        "Received event"
+    );
+
+    if is_empty_payload(&payload) && env::var("DEMO_MODE").is_ok_and(|v| !v.is_empty() && v != "false") {
+        info!("Empty invocation in demo mode, returning usage");
+        return Ok(Response {
+            req_id: request_id,
+            response: serde_json::to_string(&usage())?,
+        });
+    }
+
+    // The client's own id, distinct from `req_id`; the payload field wins
+    // over the header.
+    let correlation_id = payload
+        .correlation_id
+        .clone()
+        .or_else(|| header_correlation_id(&event.payload));
+    if let Some(correlation_id) = &correlation_id {
+        Span::current().record("correlation_id", correlation_id.as_str());
+    }
+
+    let budget = FetchBudget::new(parse_max_concurrency(payload.max_concurrency.as_deref()), max_detail_fetches());
+    if payload.ticker_symbol.as_deref().is_some_and(|t| t.contains(',')) {
+        return handle_watchlist(payload, request_id, correlation_id, &budget).await;
+    }
+    handle_query(payload, request_id, correlation_id, &budget).await
+}
+
+// One underlying's request, from validation to the finished response. Its
+// Polygon requests draw on `budget`, which the caller may share with other
+// underlyings.
+async fn handle_query(
+    payload: Payload,
+    request_id: String,
+    correlation_id: Option<String>,
+    budget: &FetchBudget,
+) -> Result<Response, Error> {
+    if let Err((field, message)) = validate_core_parameters(&payload) {
+        return invalid_field_response(request_id, field, message);
+    }
+
+    let mut warnings: Vec<String> = Vec::new();
+    let Some(api_key) = resolve_api_key(payload.api_key.clone(), &mut warnings) else {
+        return invalid_field_response(request_id, "api_key", MISSING_API_KEY_MESSAGE.to_string());
+    };
+    let cache_key = response_cache_key(&payload, &api_key);
+
+    let extra_params = payload.extra_params.unwrap_or_default();
+    let max_extra_params = env_limit("MAX_EXTRA_PARAMS", DEFAULT_MAX_EXTRA_PARAMS);
+    if extra_params.len() > max_extra_params {
+        return error_response(
+            request_id,
+            400,
+            format!(
+                "extra_params has {} entries, over the limit of {}",
+                extra_params.len(),
+                max_extra_params
+            ),
+        );
+    }
+
+    // Extract parameters
+    let ticker_symbol = payload
+        .ticker_symbol
+        .map(|t| t.trim().to_uppercase())
+        .unwrap_or_default();
+    // Both were checked by validate_core_parameters.
+    let limit = payload.limit.as_ref().and_then(NumericParam::value).copied().unwrap_or(10);
+    let days_forward = payload.days_forward.as_ref().and_then(NumericParam::value).copied().unwrap_or(30);
+    let mut contract_type = payload
+        .contract_type
+        .map(|c| c.trim().to_lowercase())
+        .unwrap_or("call".to_string());
+    // "both" is spelled as an empty contract type: the listing then omits
+    // the filter and returns calls and puts together, so `limit` still caps
+    // the total rather than each side.
+    if contract_type.trim().eq_ignore_ascii_case("both") {
+        contract_type.clear();
+    }
+    let mut strikes = payload.strikes.unwrap_or_default();
+    // Both bounds were checked by validate_core_parameters.
+    let strike_range: (Option<f64>, Option<f64>) = (
+        payload.strike_price_gte.as_deref().and_then(|k| k.trim().parse().ok()),
+        payload.strike_price_lte.as_deref().and_then(|k| k.trim().parse().ok()),
+    );
+    // days_back and strike_increment were checked by validate_core_parameters.
+    let days_back: Option<i64> = payload.days_back.as_deref().and_then(|d| d.trim().parse().ok());
+    let expired = payload.expired;
+    let strike_increment: Option<f64> = payload.strike_increment.as_deref().and_then(|k| k.trim().parse().ok());
+    // Checked by validate_core_parameters.
+    let expiration_weekday = payload.expiration_weekday.as_deref().and_then(parse_weekday);
+    // Whole numbers, checked by validate_core_parameters.
+    let strike_offsets: Vec<i64> = payload
+        .strike_offsets
+        .unwrap_or_default()
+        .iter()
+        .map(|offset| *offset as i64)
+        .collect();
+    let precision: usize = payload
+        .precision
+        .and_then(|p| p.parse().ok())
+        .unwrap_or(2);
+    let data_tier = payload
+        .data_tier
+        .as_deref()
+        .and_then(DataTier::parse)
+        .unwrap_or_else(DataTier::from_env);
+    let api_host = match payload.api_host.as_deref() {
+        Some(raw) => match resolve_api_host(raw) {
+            Ok(host) => host,
+            Err(message) => return error_response(request_id, 400, message),
+        },
+        None => env_base_url().unwrap_or(DEFAULT_API_HOST.to_string()),
+    };
+    let iv_history = payload.iv_history.unwrap_or_default();
+    let locale = payload.locale.as_deref().and_then(|tag| {
+        let locale = NumberLocale::parse(tag);
+        if locale.is_none() {
+            warnings.push(format!("Ignored locale: {:?} is not supported", tag));
+        }
+        locale
+    });
+    let max_concurrency = parse_max_concurrency(payload.max_concurrency.as_deref());
+    let requests_sent = AtomicUsize::new(0);
+    let retry = RetryPolicy {
+        max_retries: payload
+            .max_retries
+            .and_then(|r| r.parse().ok())
+            .unwrap_or(DEFAULT_MAX_RETRIES),
+        base_delay: payload
+            .retry_base_delay_ms
+            .and_then(|ms| ms.parse().ok())
+            .map(StdDuration::from_millis)
+            .unwrap_or(DEFAULT_RETRY_BASE_DELAY),
+        requests_sent: &requests_sent,
+    };
+    let page_timeout = payload
+        .page_timeout_ms
+        .and_then(|ms| ms.parse().ok())
+        .filter(|&ms| ms > 0)
+        .map(StdDuration::from_millis)
+        .unwrap_or(DEFAULT_PAGE_TIMEOUT);
+    let listing_fallback = payload.listing_fallback.unwrap_or(false);
+    // Which source's strike to trust when both carry one.
+    let use_listing_strike = match payload.strike_source.as_deref().map(str::trim) {
+        None | Some("snapshot") => false,
+        Some("listing") => true,
+        Some(other) => {
+            return invalid_field_response(
+                request_id,
+                "strike_source",
+                format!("strike_source {:?} must be \"listing\" or \"snapshot\"", other),
+            );
+        }
+    };
+    let ramp_concurrency = payload.ramp_concurrency.unwrap_or(false);
+    let tag = payload.tag;
+    let sort_by = payload.sort_by;
+    let sort_descending = payload
+        .sort_order
+        .map(|o| o.trim().eq_ignore_ascii_case("desc"))
+        .unwrap_or(false);
+    let top_n = payload.top_n.as_ref().and_then(NumericParam::value).copied();
+    let output_format = payload.output_format;
+    let select = payload.select;
+    let fetch_spot = payload.fetch_spot.unwrap_or(false);
+    let include_zero_oi = payload.include_zero_oi.unwrap_or(true);
+    let min_open_interest: Option<u64> = payload.min_open_interest.and_then(|n| n.trim().parse().ok());
+    let min_volume: Option<u64> = payload.min_volume.and_then(|n| n.trim().parse().ok());
+    let require_complete = payload.require_complete.unwrap_or(false);
+    let pair_by_strike = payload.pair_by_strike.unwrap_or(false);
+    let include_term_structure = payload.include_term_structure.unwrap_or(false);
+    let callback_url = match payload.callback_url.as_deref().map(validate_callback_url) {
+        Some(Ok(url)) => Some(url),
+        Some(Err(message)) => return invalid_field_response(request_id, "callback_url", message),
+        None => None,
+    };
+    let bypass_cache = payload.bypass_cache.unwrap_or(false);
+    // Fresh data skips the response cache and the listing cache alike.
+    let max_stale_secs: Option<u64> = payload
+        .max_stale_secs
+        .and_then(|s| s.parse().ok())
+        .filter(|_| !bypass_cache);
+    let listing_cache_ttl =
+        StdDuration::from_secs(env_limit("LISTING_CACHE_TTL_SECS", DEFAULT_LISTING_CACHE_TTL_SECS) as u64);
+    let action = payload.action;
+    // Quote-only pollers get none of the enrichment, history included.
+    let quote_only = output_format.as_deref() == Some("quote_only");
+    let history_days: Option<i64> = if payload.include_history.unwrap_or(false) && !quote_only {
+        // Checked by validate_core_parameters.
+        Some(payload.history_days.and_then(|d| d.trim().parse().ok()).unwrap_or(30))
+    } else {
+        None
+    };
+    let fetch_both_sides = pair_by_strike || action.as_deref() == Some("atm_straddle");
+    let sample_strikes: Option<usize> = payload.sample_strikes.and_then(|n| n.parse().ok());
+    // Randomized features draw from one seeded RNG so results are
+    // reproducible; without an explicit seed a random one is chosen and
+    // reported back in the metadata.
+    let seed: u64 = payload
+        .seed
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(rand::random);
+    let moneyness_pct = validate_pct("moneyness_pct", payload.moneyness_pct.as_deref(), &mut warnings);
+    let max_otm_pct = validate_pct("max_otm_pct", payload.max_otm_pct.as_deref(), &mut warnings);
+    let max_spread_pct = validate_pct("max_spread_pct", payload.max_spread_pct.as_deref(), &mut warnings);
+    let min_gamma = payload.min_gamma.as_ref().and_then(NumericParam::value).copied();
+    let max_gamma = payload.max_gamma.as_ref().and_then(NumericParam::value).copied();
+    let max_cost = payload.max_cost.as_ref().and_then(NumericParam::value).copied();
+    let only_above_baseline = payload.only_above_baseline.unwrap_or(false);
+    let per_expiration_top_n: Option<usize> = payload.per_expiration_top_n.and_then(|n| n.parse().ok());
+    let num_expirations: Option<usize> = payload.num_expirations.and_then(|n| n.trim().parse().ok());
+    let count_before_filter = payload.count_before_filter.unwrap_or(false);
+    let mut format_options = FormatOptions {
+        precision,
+        locale,
+        use_ask_for_cost: payload.use_ask_for_cost.unwrap_or(false),
+        include_greeks: payload.include_greeks.unwrap_or(false),
+        baseline_iv: payload.baseline_iv.as_deref().and_then(|iv| iv.parse().ok()),
+        fetched_spot: None,
+        fetched_spot_updated_ns: None,
+        max_spot_age_secs: payload.max_spot_age_secs.as_deref().and_then(|a| a.trim().parse().ok()),
+        now_ns: Utc::now().timestamp_nanos_opt().unwrap_or(i64::MAX),
+        today: Local::now().date_naive(),
+    };
+    let window = ExpirationWindow::resolve(
+        Local::now().date_naive(),
+        days_forward,
+        // Checked by validate_core_parameters.
+        payload
+            .min_dte
+            .and_then(|d| d.trim().parse().ok())
+            .unwrap_or(0),
+        days_back,
+        payload.expiration_start.as_deref().and_then(parse_date),
+        payload.expiration_end.as_deref().and_then(parse_date),
+    );
+    if window.is_empty() {
+        return error_response(
+            request_id,
+            400,
+            format!(
+                "Conflicting expiration parameters: the window starts {} (the later of today + min_dte, or today - days_back, and expiration_start) but ends {} (the earlier of today + days_forward and expiration_end)",
+                window.from.format("%Y-%m-%d"),
+                window.to.format("%Y-%m-%d")
+            ),
+        );
+    }
+
+    // A vertical needs one leg bought and one sold at different strikes of
+    // the same type; the listing is narrowed to just those two strikes.
+    let vertical_legs = match payload.strategy.as_deref() {
+        None => None,
+        Some("vertical") => {
+            let long_strike: Option<f64> = payload.long_strike.as_deref().and_then(|k| k.parse().ok());
+            let short_strike: Option<f64> = payload.short_strike.as_deref().and_then(|k| k.parse().ok());
+            let (Some(long_strike), Some(short_strike)) = (long_strike, short_strike) else {
+                return error_response(
+                    request_id,
+                    400,
+                    "strategy \"vertical\" requires numeric long_strike and short_strike".to_string(),
+                );
+            };
+            if (long_strike - short_strike).abs() < STRIKE_EPSILON {
+                return error_response(
+                    request_id,
+                    400,
+                    "long_strike and short_strike must differ for a vertical".to_string(),
+                );
+            }
+            if contract_type != "call" && contract_type != "put" {
+                return error_response(
+                    request_id,
+                    400,
+                    "strategy \"vertical\" requires contract_type \"call\" or \"put\"".to_string(),
+                );
+            }
+            strikes = vec![long_strike, short_strike];
+            Some((long_strike, short_strike))
+        }
+        Some(other) => {
+            return error_response(request_id, 400, format!("Unknown strategy {:?}", other));
+        }
+    };
+
+    info!(
+        ticker = %ticker_symbol,
+        limit,
+        days_forward,
+        %contract_type,
+        "Using parameters"
+    );
+    debug!(
+        ?strikes,
+        ?strike_range,
+        ?strike_increment,
+        ?strike_offsets,
+        ?vertical_legs,
+        ?expiration_weekday,
+        precision,
+        ?locale,
+        ?data_tier,
+        %api_host,
+        max_concurrency,
+        max_retries = retry.max_retries,
+        retry_base_delay = ?retry.base_delay,
+        ?page_timeout,
+        ?window,
+        ?days_back,
+        ?expired,
+        listing_fallback,
+        use_listing_strike,
+        ramp_concurrency,
+        ?tag,
+        use_ask_for_cost = format_options.use_ask_for_cost,
+        include_greeks = format_options.include_greeks,
+        baseline_iv = ?format_options.baseline_iv,
+        only_above_baseline,
+        ?sort_by,
+        sort_descending,
+        ?top_n,
+        ?output_format,
+        ?select,
+        ?moneyness_pct,
+        ?max_otm_pct,
+        ?max_spread_pct,
+        ?min_gamma,
+        ?max_gamma,
+        ?max_cost,
+        ?per_expiration_top_n,
+        ?num_expirations,
+        count_before_filter,
+        fetch_spot,
+        include_zero_oi,
+        ?min_open_interest,
+        ?min_volume,
+        require_complete,
+        pair_by_strike,
+        include_term_structure,
+        ?callback_url,
+        ?action,
+        ?history_days,
+        ?sample_strikes,
+        seed,
+        ?max_stale_secs,
+        bypass_cache,
+        ?listing_cache_ttl,
+        "Resolved parameters"
+    );
+
+    // Every effective parameter after defaults, clamping, env overrides and
+    // strategy expansion, in the forms the payload accepts so the object can
+    // be sent back as a request. The API key and callback URL are left out;
+    // unset parameters are dropped.
+    let resolved_request = if payload.include_resolved_request.unwrap_or(false) {
+        let resolved: serde_json::Map<String, Value> = [
+            ("ticker_symbol", json!(ticker_symbol)),
+            ("limit", json!(limit)),
+            ("days_forward", json!(days_forward)),
+            ("days_back", json!(days_back.map(|d| d.to_string()))),
+            ("expired", json!(expired)),
+            ("expiration_start", json!(window.from.format("%Y-%m-%d").to_string())),
+            ("expiration_end", json!(window.to.format("%Y-%m-%d").to_string())),
+            ("contract_type", json!(if contract_type.is_empty() { "both" } else { contract_type.as_str() })),
+            ("strikes", json!((!strikes.is_empty()).then_some(&strikes))),
+            ("strike_price_gte", json!(strike_range.0.map(|k| k.to_string()))),
+            ("strike_price_lte", json!(strike_range.1.map(|k| k.to_string()))),
+            ("strike_increment", json!(strike_increment.map(|k| k.to_string()))),
+            ("strike_offsets", json!((!strike_offsets.is_empty()).then_some(&strike_offsets))),
+            ("expiration_weekday", json!(expiration_weekday.map(|weekday| weekday.to_string()))),
+            ("strategy", json!(payload.strategy)),
+            ("long_strike", json!(vertical_legs.map(|(long_strike, _)| long_strike.to_string()))),
+            ("short_strike", json!(vertical_legs.map(|(_, short_strike)| short_strike.to_string()))),
+            ("precision", json!(precision.to_string())),
+            ("locale", json!(payload.locale.as_ref().filter(|_| locale.is_some()))),
+            ("data_tier", json!(if data_tier == DataTier::Delayed { "delayed" } else { "realtime" })),
+            ("api_host", json!(payload.api_host)),
+            ("max_concurrency", json!(max_concurrency.to_string())),
+            ("max_retries", json!(retry.max_retries.to_string())),
+            ("retry_base_delay_ms", json!(retry.base_delay.as_millis().to_string())),
+            ("page_timeout_ms", json!(page_timeout.as_millis().to_string())),
+            ("ramp_concurrency", json!(ramp_concurrency)),
+            ("listing_fallback", json!(listing_fallback)),
+            ("strike_source", json!(if use_listing_strike { "listing" } else { "snapshot" })),
+            ("extra_params", json!((!extra_params.is_empty()).then_some(&extra_params))),
+            ("fetch_spot", json!(fetch_spot)),
+            ("max_spot_age_secs", json!(format_options.max_spot_age_secs.map(|a| a.to_string()))),
+            ("include_greeks", json!(format_options.include_greeks)),
+            ("use_ask_for_cost", json!(format_options.use_ask_for_cost)),
+            ("baseline_iv", json!(format_options.baseline_iv.map(|iv| iv.to_string()))),
+            ("only_above_baseline", json!(only_above_baseline)),
+            ("iv_history", json!((!iv_history.is_empty()).then_some(&iv_history))),
+            ("include_zero_oi", json!(include_zero_oi)),
+            ("min_open_interest", json!(min_open_interest.map(|n| n.to_string()))),
+            ("min_volume", json!(min_volume.map(|n| n.to_string()))),
+            ("require_complete", json!(require_complete)),
+            ("moneyness_pct", json!(moneyness_pct.map(|pct| pct.to_string()))),
+            ("max_otm_pct", json!(max_otm_pct.map(|pct| pct.to_string()))),
+            ("max_spread_pct", json!(max_spread_pct.map(|pct| pct.to_string()))),
+            ("min_gamma", json!(min_gamma.map(|g| g.to_string()))),
+            ("max_gamma", json!(max_gamma.map(|g| g.to_string()))),
+            ("max_cost", json!(max_cost.map(|c| c.to_string()))),
+            ("num_expirations", json!(num_expirations.map(|n| n.to_string()))),
+            ("count_before_filter", json!(count_before_filter)),
+            ("per_expiration_top_n", json!(per_expiration_top_n.map(|n| n.to_string()))),
+            ("sample_strikes", json!(sample_strikes.map(|n| n.to_string()))),
+            ("seed", json!(seed.to_string())),
+            ("sort_by", json!(sort_by)),
+            ("sort_order", json!(sort_by.as_ref().map(|_| if sort_descending { "desc" } else { "asc" }))),
+            ("top_n", json!(top_n.map(|n| n.to_string()))),
+            ("pair_by_strike", json!(pair_by_strike)),
+            ("action", json!(action)),
+            ("include_term_structure", json!(include_term_structure)),
+            ("include_history", json!(history_days.is_some())),
+            ("history_days", json!(history_days.map(|d| d.to_string()))),
+            ("output_format", json!(output_format)),
+            ("select", json!(select)),
+            ("tag", json!(tag)),
+            ("max_stale_secs", json!(max_stale_secs.map(|secs| secs.to_string()))),
+            ("bypass_cache", json!(bypass_cache)),
+        ]
+        .into_iter()
+        .filter(|(_, value)| !value.is_null())
+        .map(|(field, value)| (field.to_string(), value))
+        .collect();
+        Some(Value::Object(resolved))
+    } else {
+        None
+    };
+
+    let client = http_client();
+
+    if let Some(max_stale_secs) = max_stale_secs {
+        if let Some((age, mut body)) = response_cache_lookup(&cache_key, max_stale_secs) {
+            info!(age_secs = age.as_secs(), "Serving cached response");
+            body["metadata"]["cache"] = json!({ "hit": true, "age_secs": age.as_secs() });
+            body["metadata"]["api_calls_made"] = json!(0);
+            return finish_query(body, None, request_id, correlation_id.as_deref(), callback_url.as_deref(), &api_key)
+                .await;
+        }
+    }
+    let semaphore = &budget.semaphore;
+    let mut listing_cached = false;
+    let listing = {
+        let contract_query = ContractQuery {
+            ticker_symbol: &ticker_symbol,
+            limit: limit as usize,
+            window,
+            contract_type: if fetch_both_sides { "" } else { &contract_type },
+            strikes: &strikes,
+            strike_range,
+            expired,
+            strike_increment,
+            expiration_weekday,
+            extra_params: &extra_params,
+            page_timeout,
+        };
+        let listing_key = listing_cache_key(&api_host, &contract_query);
+        let cached_listing = if bypass_cache || listing_cache_ttl.is_zero() {
+            None
+        } else {
+            listing_cache_lookup(&listing_key, listing_cache_ttl)
+        };
+        match cached_listing {
+            Some(listing) => {
+                info!("Using cached contract listing");
+                listing_cached = true;
+                listing
+            }
+            None => {
+                let _permit = semaphore.acquire().await?;
+                let listing =
+                    get_relevant_option_contracts(&client, &api_key, &api_host, &contract_query, retry).await?;
+                // A listing cut short by an error is never reused.
+                if !listing_cache_ttl.is_zero() && !listing.failed {
+                    listing_cache_store(listing_key, &listing);
+                }
+                listing
+            }
+        }
+    };
+    let mut contract_tickers = listing.tickers;
+
+    debug!(?contract_tickers, "Retrieved contract tickers");
+
+    // Strike offsets are relative to ATM, so they need a spot before any
+    // snapshot has been fetched. Quote-only responses have no other use for
+    // one.
+    if (fetch_spot && !quote_only) || !strike_offsets.is_empty() {
+        let _permit = semaphore.acquire().await?;
+        let fetched = get_underlying_price(&client, &api_key, &api_host, &ticker_symbol, retry).await?;
+        format_options.fetched_spot = fetched.map(|(price, _)| price);
+        format_options.fetched_spot_updated_ns = fetched.and_then(|(_, updated_ns)| updated_ns);
+        debug!(?fetched, "Fetched underlying price");
+    }
+
+    let mut resolved_offset_strikes: Option<Vec<f64>> = None;
+    if !strike_offsets.is_empty() {
+        match format_options.fetched_spot {
+            Some(spot) => {
+                let strikes =
+                    resolve_strike_offsets(&listing.entries, &contract_tickers, spot, &strike_offsets);
+                contract_tickers.retain(|ticker| {
+                    listing
+                        .entries
+                        .get(ticker)
+                        .map(|entry| matches_any_strike(entry, &strikes))
+                        .unwrap_or(false)
+                });
+                debug!(?strike_offsets, ?strikes, "Resolved strike offsets");
+                resolved_offset_strikes = Some(strikes);
+            }
+            None => warnings.push(
+                "strike_offsets ignored: the underlying price could not be fetched".to_string(),
+            ),
+        }
+    }
+
+    let mut sampled_strikes: Option<Vec<f64>> = None;
+    if let Some(sample_size) = sample_strikes {
+        let mut rng = StdRng::seed_from_u64(seed);
+        let strikes = sample_listing_strikes(&listing.entries, &contract_tickers, sample_size, &mut rng);
+        contract_tickers.retain(|ticker| {
+            listing
+                .entries
+                .get(ticker)
+                .map(|entry| matches_any_strike(entry, &strikes))
+                .unwrap_or(false)
+        });
+        debug!(?strikes, seed, "Sampled strikes");
+        sampled_strikes = Some(strikes);
+    }
+
+    let max_detail_fetches = budget.max_fetches;
+    let granted_fetches = budget.take_fetches(contract_tickers.len());
+    let truncated_count = contract_tickers.len() - granted_fetches;
+    if truncated_count > 0 {
+        warn!(truncated_count, max_detail_fetches, "Dropping contracts over the MAX_DETAIL_FETCHES cap");
+        contract_tickers.truncate(granted_fetches);
+    }
+
+    // Fetch details concurrently for better performance
+    let jobs: Vec<(&str, &str)> = contract_tickers
+        .iter()
+        .map(|ticker| (ticker_symbol.as_str(), ticker.as_str()))
+        .collect();
+    let contracts_data = fetch_all_contract_details(
+        &client,
+        &api_key,
+        &jobs,
+        &api_host,
+        semaphore,
+        if ramp_concurrency {
+            FetchPacing::Ramp(max_concurrency)
+        } else {
+            FetchPacing::Steady(max_concurrency)
+        },
+        retry,
+    )
+    .await;
+
+    let mut snapshots: Vec<Value> = Vec::new();
+    let mut listing_only: Vec<Value> = Vec::new();
+    // Contracts whose snapshot couldn't be fetched, for clients to retry.
+    let mut fetch_errors: Vec<Value> = Vec::new();
+    for ((_, option_ticker), result) in jobs.iter().zip(contracts_data) {
+        match result {
+            Ok(mut contract) if !contract.is_null() => {
+                if use_listing_strike {
+                    let listed_strike = listing
+                        .entries
+                        .get(*option_ticker)
+                        .and_then(|entry| entry["strike_price"].as_f64());
+                    if let Some(listed_strike) = listed_strike {
+                        contract["details"]["strike_price"] = json!(listed_strike);
+                    }
+                }
+                // Expiration-derived fields are simply omitted for such a
+                // contract; the warning says why.
+                if let Some(expiration) = contract["details"]["expiration_date"].as_str() {
+                    if parse_date(expiration).is_none() {
+                        warnings.push(format!(
+                            "{}: unparseable expiration_date {:?}; expiration fields omitted",
+                            option_ticker, expiration
+                        ));
+                    }
+                }
+                // The snapshot is the fresher source, so it wins; the
+                // disagreement is only reported.
+                let listed_type = listing.entries.get(*option_ticker).and_then(|entry| entry["contract_type"].as_str());
+                let snapshot_type = contract["details"]["contract_type"].as_str();
+                if let (Some(listed_type), Some(snapshot_type)) = (listed_type, snapshot_type) {
+                    if listed_type != snapshot_type {
+                        warnings.push(format!(
+                            "{}: listed as {} but snapshot reports {}; using the snapshot",
+                            option_ticker, listed_type, snapshot_type
+                        ));
+                    }
+                }
+                snapshots.push(contract);
+                continue;
+            }
+            Ok(_) => debug!(ticker = %option_ticker, "Contract data is null"),
+            Err(e) => {
+                // Transport errors quote the request URL, key included.
+                let (status, detail) = match e.downcast_ref::<SnapshotError>() {
+                    Some(snapshot_error) => (snapshot_error.status, snapshot_error.message.clone()),
+                    None => (None, e.to_string()),
+                };
+                let message = redact_api_key(&format!("{}: {}", option_ticker, e), &api_key);
+                error!(error = %message, "Error fetching contract details");
+                warnings.push(message);
+                fetch_errors.push(json!({
+                    "ticker": option_ticker,
+                    "status": status,
+                    "message": redact_api_key(&detail, &api_key),
+                }));
+            }
+        }
+        if listing_fallback {
+            if let Some(entry) = listing.entries.get(*option_ticker) {
+                listing_only.push(format_listing_entry(entry).to_value(&format_options));
+            }
+        }
+    }
+
+    if listing.dropped_without_ticker > 0 {
+        warnings.push(format!(
+            "{} listed contracts had no ticker and were skipped",
+            listing.dropped_without_ticker
+        ));
+    }
+    if truncated_count > 0 {
+        warnings.push(format!(
+            "{} contracts were dropped because the invocation hit the cap of {} snapshot fetches",
+            truncated_count, max_detail_fetches
+        ));
+    }
+
+    // A partial result would keep being served for max_stale_secs after the
+    // outage that caused it, so only complete ones are cached.
+    let cacheable = !listing.failed && fetch_errors.is_empty();
+
+    // Quote-only pollers get the fetched quotes as they are: no formatting,
+    // filtering, sorting or other enrichment.
+    if quote_only {
+        let body = json!({
+            "option_contracts": to_quote_only(&snapshots),
+            "metadata": {
+                "api_calls_made": requests_sent.load(Ordering::Relaxed),
+                "dropped_without_ticker": listing.dropped_without_ticker,
+                "data_delayed": data_tier == DataTier::Delayed,
+                "expiration_window": window.to_json(),
+                "listing_cached": listing_cached,
+                "truncated": truncated_count > 0,
+                "truncated_count": truncated_count,
+            },
+            "warnings": warnings,
+            "errors": fetch_errors,
+        });
+        return finish_query(
+            body,
+            cacheable.then_some(cache_key),
+            request_id,
+            correlation_id.as_deref(),
+            callback_url.as_deref(),
+            &api_key,
+        )
+        .await;
+    }
+
+    // With count_before_filter the nearest expirations are chosen from
+    // everything fetched, so one emptied by the filters below still counts
+    // toward `num_expirations`.
+    let expirations_before_filter = match num_expirations {
+        Some(n) if count_before_filter => Some(nearest_expirations(&snapshots, &listing_only, n)),
+        _ => None,
+    };
+
+    if !include_zero_oi {
+        snapshots.retain(|contract| contract["open_interest"].as_u64().unwrap_or(0) > 0);
+        // Listing entries carry no open interest, so they can't pass this filter.
+        listing_only.clear();
+    }
+
+    if min_open_interest.is_some() || min_volume.is_some() {
+        // A missing open interest or volume counts as below the threshold.
+        let meets = |value: Option<u64>, min: Option<u64>| min.is_none_or(|min| value.is_some_and(|v| v >= min));
+        let before = snapshots.len();
+        snapshots.retain(|contract| {
+            meets(contract["open_interest"].as_u64(), min_open_interest)
+                && meets(contract["day"]["volume"].as_u64(), min_volume)
+        });
+        if snapshots.len() < before {
+            warnings.push(format!(
+                "{} contracts below min_open_interest or min_volume were dropped",
+                before - snapshots.len()
+            ));
+        }
+        listing_only.clear();
+    }
+
+    if require_complete {
+        let before = snapshots.len();
+        snapshots.retain(has_complete_data);
+        if snapshots.len() < before {
+            warnings.push(format!(
+                "{} contracts with incomplete data were dropped (require_complete)",
+                before - snapshots.len()
+            ));
+        }
+        // Listing entries never carry market data.
+        listing_only.clear();
+    }
+
+    if moneyness_pct.is_some() || max_otm_pct.is_some() {
+        snapshots.retain(|contract| {
+            let strike = contract["details"]["strike_price"].as_f64();
+            let spot = resolve_spot(contract, format_options.fetched_spot).0;
+            let (Some(strike), Some(spot)) = (strike, spot) else {
+                return false;
+            };
+            if spot <= 0.0 {
+                return false;
+            }
+            let distance_pct = (strike - spot) / spot * 100.0;
+            let otm_pct = match contract["details"]["contract_type"].as_str() {
+                Some("put") => -distance_pct,
+                _ => distance_pct,
+            };
+            moneyness_pct.is_none_or(|pct| distance_pct.abs() <= pct)
+                && max_otm_pct.is_none_or(|pct| otm_pct <= pct)
+        });
+        listing_only.clear();
+    }
+    if let Some(max_spread_pct) = max_spread_pct {
+        snapshots.retain(|contract| {
+            compute_spread(&contract["last_quote"])
+                .1
+                .is_some_and(|rel| rel * 100.0 <= max_spread_pct)
+        });
+        listing_only.clear();
+    }
+
+    if min_gamma.is_some() || max_gamma.is_some() {
+        snapshots.retain(|contract| {
+            contract["greeks"]["gamma"].as_f64().is_some_and(|gamma| {
+                min_gamma.is_none_or(|min| gamma >= min) && max_gamma.is_none_or(|max| gamma <= max)
+            })
+        });
+        listing_only.clear();
+    }
+
+    if only_above_baseline {
+        match format_options.baseline_iv {
+            Some(baseline_iv) => {
+                snapshots.retain(|contract| {
+                    contract["implied_volatility"]
+                        .as_f64()
+                        .is_some_and(|iv| iv > baseline_iv)
+                });
+                listing_only.clear();
+            }
+            None => warnings.push("Ignored only_above_baseline: no numeric baseline_iv was given".to_string()),
+        }
+    }
+    if let Some(max_cost) = max_cost {
+        // Priced at the ask for a conservative budget; the midpoint fallback
+        // used for `contract_cost` would understate the fill, so contracts
+        // without an ask are dropped.
+        snapshots.retain(|contract| match compute_contract_cost(contract, true) {
+            (Some(cost), "ask", _) => cost <= max_cost,
+            _ => false,
+        });
+        listing_only.clear();
+    }
+
+    if let Some(n) = num_expirations {
+        let kept = expirations_before_filter.unwrap_or_else(|| nearest_expirations(&snapshots, &listing_only, n));
+        snapshots.retain(|contract| {
+            contract["details"]["expiration_date"]
+                .as_str()
+                .is_some_and(|expiration| kept.contains(expiration))
+        });
+        listing_only.retain(|contract| {
+            contract["expiration_date"]
+                .as_str()
+                .is_some_and(|expiration| kept.contains(expiration))
+        });
+    }
+
+    if let Some(per_expiration_top_n) = per_expiration_top_n {
+        snapshots = keep_most_liquid_per_expiration(snapshots, per_expiration_top_n);
+        listing_only.clear();
+    }
+
+    // Process and format the data
+    let summaries: Vec<OptionContract> = snapshots
+        .iter()
+        .map(|contract| format_contract(contract, &format_options))
+        .collect();
+    let stale_spots = summaries.iter().filter(|contract| contract.spot_stale == Some(true)).count();
+    if stale_spots > 0 {
+        warnings.push(format!(
+            "{} contracts were computed from an underlying price older than max_spot_age_secs",
+            stale_spots
+        ));
+    }
+    let mut formatted_contracts: Vec<Value> = summaries
+        .iter()
+        .map(|contract| contract.to_value(&format_options))
+        .collect();
+    let all_pairs = if fetch_both_sides {
+        pair_contracts_by_strike(&snapshots, &formatted_contracts)
+    } else {
+        Vec::new()
+    };
+    let atm_straddle = if action.as_deref() == Some("atm_straddle") {
+        let spot = snapshots
+            .first()
+            .and_then(|contract| resolve_spot(contract, format_options.fetched_spot).0);
+        Some(spot.and_then(|spot| find_atm_straddle(&all_pairs, spot)))
+    } else {
+        None
+    };
+    let vertical = vertical_legs.map(|(long_strike, short_strike)| {
+        find_vertical(&snapshots, &formatted_contracts, long_strike, short_strike)
+    });
+    let strike_pairs = if pair_by_strike { Some(all_pairs) } else { None };
+    let term_structure = if include_term_structure {
+        Some(atm_term_structure(&snapshots, format_options.fetched_spot, format_options.today))
+    } else {
+        None
+    };
+    formatted_contracts.extend(listing_only.iter().cloned());
+    if let Some(sort_by) = &sort_by {
+        sort_contracts(&mut formatted_contracts, sort_by, sort_descending, locale.unwrap_or_default());
+    }
+    if let Some(top_n) = top_n {
+        formatted_contracts.truncate(top_n);
+    }
+    if let Some(history_days) = history_days {
+        // Only the contracts actually returned get a history, since each one
+        // costs an extra request.
+        let to = format_options.today;
+        let from = to - Duration::days(history_days);
+        let histories = join_all(formatted_contracts.iter().map(|contract| {
+            let ticker = contract["ticker"].as_str().unwrap_or("").to_string();
+            let (client, api_key, api_host, semaphore) = (&client, &api_key, &api_host, semaphore);
+            async move {
+                if ticker.is_empty() || ticker == "N/A" {
+                    return Ok(Value::Null);
+                }
+                let _permit = semaphore.acquire().await?;
+                get_contract_history(client, api_key, api_host, &ticker, from, to, retry).await
+            }
+        }))
+        .await;
+        for (contract, history) in formatted_contracts.iter_mut().zip(histories) {
+            contract["history"] = history.unwrap_or_else(|e: Error| {
+                error!(error = %redact_api_key(&e.to_string(), &api_key), "Error fetching history");
+                Value::Null
+            });
+        }
+    }
+    if let Some(tag) = &tag {
+        for contract in formatted_contracts.iter_mut() {
+            contract["tag"] = json!(tag);
+        }
+    }
+
+    debug!(?formatted_contracts, "Formatted contracts");
+
+    let divergent_spots = formatted_contracts
+        .iter()
+        .filter(|c| !c["spot_divergence"].is_null())
+        .count();
+    if divergent_spots > 0 {
+        warnings.push(format!(
+            "{} contracts had an embedded underlying price that disagreed with the fetched spot",
+            divergent_spots
+        ));
+    }
+    if matches!(atm_straddle, Some(None)) {
+        warnings.push("No quoted ATM call/put pair was found for the nearest expiration".to_string());
+    }
+    if matches!(vertical, Some(None)) {
+        warnings.push("No expiration in the window had quotes for both vertical legs".to_string());
+    }
+    if !listing_only.is_empty() {
+        warnings.push(format!(
+            "{} contracts could not be fetched and were returned from listing data only",
+            listing_only.len()
+        ));
+    }
+
+    let mut metadata = json!({
+        "api_calls_made": requests_sent.load(Ordering::Relaxed),
+        "dropped_without_ticker": listing.dropped_without_ticker,
+        "data_delayed": data_tier == DataTier::Delayed,
+        "expiration_window": window.to_json(),
+        "listing_cached": listing_cached,
+        "truncated": truncated_count > 0,
+        "truncated_count": truncated_count,
+    });
+    metadata["coverage"] = json!({
+        "returned": formatted_contracts.len(),
+        "total_in_window": listing.total_in_window,
+    });
+    let count_type = |contract_type: &str| {
+        formatted_contracts
+            .iter()
+            .filter(|contract| contract["contract_type"].as_str() == Some(contract_type))
+            .count()
+    };
+    metadata["counts"] = json!({ "calls": count_type("call"), "puts": count_type("put") });
+    if let Some(tag) = &tag {
+        metadata["tag"] = json!(tag);
+    }
+    if let Some(resolved_offset_strikes) = &resolved_offset_strikes {
+        metadata["resolved_offset_strikes"] = json!(resolved_offset_strikes);
+    }
+    if let Some(sampled_strikes) = &sampled_strikes {
+        metadata["sampled_strikes"] = json!(sampled_strikes);
+        metadata["seed"] = json!(seed);
+    }
+
+    if !iv_history.is_empty() {
+        let current_iv = mean_implied_volatility(&snapshots);
+        metadata["current_iv"] = json!(current_iv);
+        metadata["iv_rank"] = json!(current_iv.and_then(|iv| iv_rank(iv, &iv_history)));
+        metadata["iv_percentile"] = json!(current_iv.and_then(|iv| iv_percentile(iv, &iv_history)));
+    }
+
+    // Taken before `select` so the map keys survive a selection that drops
+    // the ticker field.
+    let tickers: Vec<String> = formatted_contracts
+        .iter()
+        .map(|contract| contract["ticker"].as_str().unwrap_or("N/A").to_string())
+        .collect();
+
+    if let Some(select) = &select {
+        formatted_contracts = formatted_contracts
+            .iter()
+            .map(|contract| apply_selection(contract, select))
+            .collect();
+    }
+
+    let option_contracts = match output_format.as_deref() {
+        Some("compact") => to_compact_format(&formatted_contracts),
+        Some("map_by_ticker") => to_ticker_map(&tickers, formatted_contracts),
+        _ => json!(formatted_contracts),
+    };
+
+    let mut body = json!({
+        "option_contracts": option_contracts,
+        "metadata": metadata,
+        "warnings": warnings,
+        "errors": fetch_errors,
+    });
+    if let Some(strike_pairs) = strike_pairs {
+        body["strike_pairs"] = json!(strike_pairs);
+    }
+    if let Some(atm_straddle) = atm_straddle {
+        body["atm_straddle"] = json!(atm_straddle);
+    }
+    if let Some(vertical) = vertical {
+        body["vertical"] = json!(vertical);
+    }
+    if let Some(term_structure) = term_structure {
+        body["term_structure"] = json!(term_structure);
+    }
+    if let Some(resolved_request) = resolved_request {
+        body["resolved_request"] = resolved_request;
+    }
+    finish_query(
+        body,
+        cacheable.then_some(cache_key),
+        request_id,
+        correlation_id.as_deref(),
+        callback_url.as_deref(),
+        &api_key,
+    )
+    .await
+}
+
+// Stores a freshly built body in the response cache under `cache_key` (a
+// cached body passes None), then adds the correlation id and delivers the
+// response to the callback, if any.
+async fn finish_query(
+    mut body: Value,
+    cache_key: Option<String>,
+    request_id: String,
+    correlation_id: Option<&str>,
+    callback_url: Option<&str>,
+    api_key: &str,
+) -> Result<Response, Error> {
+    if let Some(cache_key) = cache_key {
+        response_cache_store(cache_key, &body);
+    }
+    if let Some(correlation_id) = correlation_id {
+        body["correlation_id"] = json!(correlation_id);
+    }
+
+    let resp = Response {
+        req_id: request_id,
+        response: serde_json::to_string(&body)?,
+    };
+
+    if let Some(callback_url) = callback_url {
+        post_callback(&http_client(), callback_url, &resp, api_key).await;
+    }
+
+    Ok(resp)
+}
+
+// Most underlyings one invocation may ask for, and how many are queried at
+// once; their requests all draw on the invocation's FetchBudget.
+const DEFAULT_MAX_WATCHLIST_TICKERS: usize = 10;
+const WATCHLIST_CONCURRENCY: usize = 3;
+
+// A comma-separated `ticker_symbol` runs one query per underlying with the
+// rest of the payload unchanged, and nests each query's results under its
+// ticker: `option_contracts`, `metadata` and any other response sections
+// become objects keyed by underlying. A failed underlying is reported in
+// `warnings` rather than failing the others.
+async fn handle_watchlist(
+    mut payload: Payload,
+    request_id: String,
+    correlation_id: Option<String>,
+    budget: &FetchBudget,
+) -> Result<Response, Error> {
+    let mut tickers: Vec<String> = Vec::new();
+    for ticker in payload.ticker_symbol.take().unwrap_or_default().split(',') {
+        let ticker = ticker.trim().to_uppercase();
+        if !ticker.is_empty() && !tickers.contains(&ticker) {
+            tickers.push(ticker);
+        }
+    }
+    let max_tickers = env_limit("MAX_WATCHLIST_TICKERS", DEFAULT_MAX_WATCHLIST_TICKERS);
+    if tickers.len() > max_tickers {
+        return invalid_field_response(
+            request_id,
+            "ticker_symbol",
+            format!("ticker_symbol lists {} underlyings, over the limit of {}", tickers.len(), max_tickers),
+        );
+    }
+
+    let mut warnings: Vec<String> = Vec::new();
+    let Some(api_key) = resolve_api_key(payload.api_key.take(), &mut warnings) else {
+        return invalid_field_response(request_id, "api_key", MISSING_API_KEY_MESSAGE.to_string());
+    };
+    // Resolved once here so the per-ticker queries don't each repeat the
+    // ignored-key warning.
+    payload.api_key = (Some(api_key.as_str()) != env_api_key()).then(|| api_key.clone());
+    let callback_url = match payload.callback_url.take().as_deref().map(validate_callback_url) {
+        Some(Ok(url)) => Some(url),
+        Some(Err(message)) => return invalid_field_response(request_id, "callback_url", message),
+        None => None,
+    };
+
+    let mut queries = Vec::with_capacity(tickers.len());
+    for ticker in &tickers {
+        let mut query = payload.clone();
+        query.ticker_symbol = Some(ticker.clone());
+        if let Err((field, message)) = validate_core_parameters(&query) {
+            return invalid_field_response(request_id, field, message);
+        }
+        queries.push(query);
+    }
+    info!(?tickers, "Watchlist query");
+
+    let results: Vec<Result<Response, Error>> = stream::iter(queries)
+        .map(|query| handle_query(query, request_id.clone(), None, budget))
+        .buffered(WATCHLIST_CONCURRENCY)
+        .collect()
+        .await;
+
+    let mut body = json!({ "option_contracts": {}, "metadata": {} });
+    for (ticker, result) in tickers.iter().zip(results) {
+        let resp = match result {
+            Ok(resp) => resp,
+            Err(e) => {
+                warnings.push(format!("{}: {}", ticker, redact_api_key(&e.to_string(), &api_key)));
+                continue;
+            }
+        };
+        let Value::Object(sections) = serde_json::from_str::<Value>(&resp.response)? else {
+            continue;
+        };
+        if let Some(error) = sections.get("error") {
+            warnings.push(format!("{}: {}", ticker, error["message"].as_str().unwrap_or("request failed")));
+            continue;
+        }
+        for (section, value) in sections {
+            if section == "warnings" {
+                for warning in value.as_array().into_iter().flatten() {
+                    warnings.push(format!("{}: {}", ticker, warning.as_str().unwrap_or_default()));
+                }
+            } else {
+                body[section.as_str()][ticker.as_str()] = value;
+            }
+        }
+    }
+    body["warnings"] = json!(warnings);
+    if let Some(correlation_id) = &correlation_id {
+        body["correlation_id"] = json!(correlation_id);
+    }
+
+    let resp = Response {
+        req_id: request_id,
+        response: serde_json::to_string(&body)?,
+    };
+
+    if let Some(callback_url) = &callback_url {
+        post_callback(&http_client(), callback_url, &resp, &api_key).await;
+    }
+
+    Ok(resp)
+}
+
+// An EventBridge (CloudWatch Events) scheduled rule whose input carries a
+// watchlist in `detail.tickers`, either a list or a comma-separated string.
+fn scheduled_prewarm_tickers(event_payload: &Value) -> Option<Vec<String>> {
+    if event_payload["source"].as_str() != Some("aws.events")
+        || event_payload["detail-type"].as_str() != Some("Scheduled Event")
+    {
+        return None;
+    }
+    let tickers: Vec<String> = match &event_payload["detail"]["tickers"] {
+        Value::Array(items) => items.iter().filter_map(|item| item.as_str()).map(|s| s.to_string()).collect(),
+        Value::String(list) => list.split(',').map(|s| s.to_string()).collect(),
+        _ => return None,
+    };
+    let mut unique: Vec<String> = Vec::new();
+    for ticker in tickers {
+        let ticker = ticker.trim().to_uppercase();
+        if !ticker.is_empty() && !unique.contains(&ticker) {
+            unique.push(ticker);
+        }
+    }
+    Some(unique)
+}
+
+// Runs each watchlist ticker's query only for its side effect of filling the
+// response cache, so later requests with `max_stale_secs` are served from
+// memory. Any other `detail` fields are used as the query parameters, and
+// must match what callers send for their requests to hit the cache. The
+// response is a summary, not contracts.
+async fn handle_prewarm(tickers: Vec<String>, detail: &Value, request_id: String) -> Result<Response, Error> {
+    let mut params = detail.clone();
+    if let Some(params) = params.as_object_mut() {
+        params.remove("tickers");
+    }
+    let payload: Payload = serde_json::from_value(params).unwrap_or_default();
+    let max_tickers = env_limit("MAX_WATCHLIST_TICKERS", DEFAULT_MAX_WATCHLIST_TICKERS);
+    let mut warnings: Vec<String> = Vec::new();
+    if tickers.len() > max_tickers {
+        warnings.push(format!(
+            "Prewarmed only the first {} of {} tickers (MAX_WATCHLIST_TICKERS)",
+            max_tickers,
+            tickers.len()
+        ));
+    }
+    let tickers: Vec<String> = tickers.into_iter().take(max_tickers).collect();
+    info!(?tickers, "Prewarming response cache");
+    let budget = FetchBudget::new(parse_max_concurrency(payload.max_concurrency.as_deref()), max_detail_fetches());
+
+    let results: Vec<Result<Response, Error>> = stream::iter(tickers.iter().map(|ticker| {
+        let mut query = payload.clone();
+        query.ticker_symbol = Some(ticker.clone());
+        query
+    }))
+    .map(|query| handle_query(query, request_id.clone(), None, &budget))
+    .buffered(WATCHLIST_CONCURRENCY)
+    .collect()
+    .await;
+
+    let mut prewarmed: Vec<&str> = Vec::new();
+    for (ticker, result) in tickers.iter().zip(results) {
+        let failure = match result {
+            Ok(resp) => serde_json::from_str::<Value>(&resp.response)?["error"]["message"]
+                .as_str()
+                .map(|m| m.to_string()),
+            Err(e) => Some(redact_api_key(&e.to_string(), env_api_key().unwrap_or_default())),
+        };
+        match failure {
+            Some(message) => warnings.push(format!("{}: {}", ticker, message)),
+            None => prewarmed.push(ticker),
+        }
+    }
+
+    Ok(Response {
+        req_id: request_id,
+        response: serde_json::to_string(&json!({ "prewarmed": prewarmed, "warnings": warnings }))?,
+    })
+}
+
+// One contract in the response. Numbers are real numbers (rounded to
+// `precision` where noted) so consumers can do math without parsing strings,
+// and anything Polygon didn't supply is omitted rather than reported as
+// "N/A". Implied volatility and the relative spread are decimals (0.25 is
+// 25%).
+#[derive(Serialize, Clone, Debug, Default)]
+struct OptionContract {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ask: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ask_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    assignment_risk: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bid: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bid_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contract_cost: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    contract_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cost_basis: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cost_fallback: Option<bool>,
+    data_source: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    days_to_expiration: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delta: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expected_move: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expiration_class: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expiration_date: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    expires_on_half_day: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    extrinsic_value: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gamma: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    greeks_notional: Option<NotionalGreeks>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    hedge_shares: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    implied_volatility: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_0dte: Option<bool>,
+    // Present only when a baseline was given; null when the contract has no IV.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    iv_vs_baseline: Option<Option<IvVsBaseline>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    is_monthly: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    last_trade_price: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    leverage: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    liquidity_score: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    moneyness: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    oi_change: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    oi_change_pct: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    open_interest: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    premium: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    premium_yield: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    probability_touch: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quote_updated: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    quote_updated_ns: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spot_divergence: Option<SpotDivergence>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spot_source: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spot_stale: Option<bool>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spread_abs: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    spread_rel: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    strike_price: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    theta: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ticker: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    trading_days_to_expiration: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    underlying_price: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vega: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    volume: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    vwap: Option<f64>,
+}
+
+impl OptionContract {
+    // The JSON form used by the rest of the pipeline (sorting, selection,
+    // output formats). With a `locale` the display numbers are rendered as
+    // localized strings instead, percentages with a `%` suffix.
+    fn to_value(&self, options: &FormatOptions) -> Value {
+        let mut value = serde_json::to_value(self).unwrap_or(Value::Null);
+        let Some(locale) = options.locale else {
+            return value;
+        };
+        let precision = options.precision;
+        let mut localize = |field: &str, render: &dyn Fn(f64) -> String| {
+            if let Some(number) = value[field].as_f64() {
+                value[field] = json!(render(number));
+            }
+        };
+        for field in ["ask", "bid", "contract_cost", "last_trade_price", "premium", "spread_abs", "vwap"] {
+            localize(field, &|n| locale.format(n, precision));
+        }
+        for field in ["implied_volatility", "premium_yield", "spread_rel"] {
+            localize(field, &|n| format!("{}%", locale.format(n * 100.0, precision)));
+        }
+        for field in ["delta", "gamma", "theta", "vega"] {
+            localize(field, &|n| locale.format(n, GREEKS_PRECISION));
+        }
+        localize("strike_price", &|n| locale.localize(&n.to_string()));
+        value
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug)]
+struct IvVsBaseline {
+    // Current IV minus the baseline, in decimal terms (0.05 is 5 vol points).
+    difference: f64,
+    // Current IV / baseline; None for a zero baseline.
+    ratio: Option<f64>,
+}
+
+fn compare_to_baseline(iv: Option<f64>, baseline_iv: f64) -> Option<IvVsBaseline> {
+    let iv = iv?;
+    Some(IvVsBaseline {
+        difference: iv - baseline_iv,
+        ratio: (baseline_iv > 0.0).then(|| iv / baseline_iv),
+    })
+}
+
+fn round_to(value: f64, decimals: usize) -> f64 {
+    let factor = 10f64.powi(decimals as i32);
+    (value * factor).round() / factor
+}
+
+fn format_contract(contract: &Value, options: &FormatOptions) -> OptionContract {
+    let precision = options.precision;
+    let (spread_abs, spread_rel) = compute_spread(&contract["last_quote"]);
+    let (underlying_price, spot_source, spot_divergence) =
+        resolve_spot(contract, options.fetched_spot);
+    let expiration = contract["details"]["expiration_date"]
+        .as_str()
+        .and_then(parse_date);
+    let trading_days_to_expiration =
+        expiration.map(|date| trading_days_between(options.today, date));
+    let (oi_change, oi_change_pct) = compute_oi_change(contract);
+    let quote_updated_ns = contract["last_quote"]["last_updated"].as_i64();
+    let (contract_cost, cost_basis, cost_fallback) =
+        compute_contract_cost(contract, options.use_ask_for_cost);
+    let contract_type = contract["details"]["contract_type"].as_str();
+    let strike = contract["details"]["strike_price"].as_f64();
+    let moneyness = classify_moneyness(contract_type, strike, underlying_price);
+    let extrinsic_value = compute_extrinsic_value(
+        contract_type,
+        strike,
+        underlying_price,
+        contract["last_quote"]["midpoint"].as_f64(),
+    );
+    let greek = |name: &str| {
+        if options.include_greeks {
+            contract["greeks"][name].as_f64().map(|g| round_to(g, GREEKS_PRECISION))
+        } else {
+            None
+        }
+    };
+
+    OptionContract {
+        ask: contract["last_quote"]["ask"].as_f64().map(|a| round_to(a, precision)),
+        ask_size: contract["last_quote"]["ask_size"].as_u64(),
+        assignment_risk: assess_assignment_risk(moneyness, trading_days_to_expiration, extrinsic_value),
+        bid: contract["last_quote"]["bid"].as_f64().map(|b| round_to(b, precision)),
+        bid_size: contract["last_quote"]["bid_size"].as_u64(),
+        contract_cost: contract_cost.map(|c| round_to(c, precision)),
+        contract_type: contract_type.map(|s| s.to_string()),
+        cost_basis: Some(cost_basis),
+        cost_fallback: Some(cost_fallback),
+        data_source: "snapshot",
+        days_to_expiration: expiration.map(|date| (date - options.today).num_days()),
+        delta: greek("delta"),
+        expected_move: compute_expected_move(
+            underlying_price,
+            contract["implied_volatility"].as_f64(),
+            trading_days_to_expiration,
+        ),
+        expiration_class: expiration.map(expiration_class),
+        expiration_date: contract["details"]["expiration_date"].as_str().map(|s| s.to_string()),
+        expires_on_half_day: expiration.map(is_early_close),
+        extrinsic_value: extrinsic_value.map(|v| round_to(v, precision)),
+        gamma: greek("gamma"),
+        greeks_notional: Some(notional_greeks(contract, underlying_price)),
+        hedge_shares: hedge_shares(contract),
+        // Two more decimals than `precision`, so the percentage it stands for
+        // keeps `precision` decimals.
+        implied_volatility: contract["implied_volatility"]
+            .as_f64()
+            .map(|v| round_to(v, precision + 2)),
+        is_0dte: expiration.map(|date| date == options.today && is_trading_day(date)),
+        iv_vs_baseline: options
+            .baseline_iv
+            .map(|baseline_iv| compare_to_baseline(contract["implied_volatility"].as_f64(), baseline_iv)),
+        is_monthly: expiration.map(is_monthly_expiration),
+        last_trade_price: contract["last_trade"]["price"].as_f64().map(|p| round_to(p, precision)),
+        leverage: compute_leverage(
+            contract["greeks"]["delta"].as_f64(),
+            underlying_price,
+            contract["last_quote"]["midpoint"].as_f64(),
+        ),
+        liquidity_score: liquidity_score(contract),
+        moneyness,
+        oi_change,
+        oi_change_pct,
+        open_interest: contract["open_interest"].as_u64(),
+        premium: contract["last_quote"]["midpoint"]
+            .as_f64()
+            .map(|p| round_to(p, precision)),
+        premium_yield: compute_premium_yield(contract["last_quote"]["midpoint"].as_f64(), strike)
+            .map(|y| round_to(y, precision + 2)),
+        probability_touch: compute_probability_touch(contract["greeks"]["delta"].as_f64()),
+        quote_updated: quote_updated_ns.and_then(format_epoch_ns),
+        quote_updated_ns,
+        spot_divergence,
+        spot_source,
+        spot_stale: is_spot_stale(contract, spot_source, options),
+        spread_abs: spread_abs.map(|s| round_to(s, precision)),
+        spread_rel: spread_rel.map(|s| round_to(s, precision + 2)),
+        strike_price: contract["details"]["strike_price"].as_f64().map(round_strike),
+        theta: greek("theta"),
+        ticker: contract["details"]["ticker"].as_str().map(|s| s.to_string()),
+        trading_days_to_expiration,
+        underlying_price,
+        vega: greek("vega"),
+        volume: contract["day"]["volume"].as_u64(),
+        vwap: contract["day"]["vwap"].as_f64().map(|v| round_to(v, precision)),
+    }
+}
+
+// Decimal places for the per-contract greeks. Fixed rather than tied to
+// `precision`, since gamma is routinely below 0.01.
+const GREEKS_PRECISION: usize = 4;
+
+// Day-over-day open interest change, when the snapshot carries the prior
+// day's figure (as `previous_open_interest` or `day.previous_open_interest`).
+// The percentage is None when the prior open interest was zero.
+fn compute_oi_change(contract: &Value) -> (Option<i64>, Option<f64>) {
+    let current = contract["open_interest"].as_i64();
+    let previous = contract["previous_open_interest"]
+        .as_i64()
+        .or_else(|| contract["day"]["previous_open_interest"].as_i64());
+    match (current, previous) {
+        (Some(current), Some(previous)) => {
+            let change = current - previous;
+            let change_pct = if previous > 0 {
+                Some(change as f64 / previous as f64 * 100.0)
+            } else {
+                None
+            };
+            (Some(change), change_pct)
+        }
+        _ => (None, None),
+    }
+}
+
+// Polygon timestamps are nanoseconds since the Unix epoch.
+fn format_epoch_ns(nanos: i64) -> Option<String> {
+    let seconds = nanos.div_euclid(1_000_000_000);
+    let subsec_nanos = nanos.rem_euclid(1_000_000_000) as u32;
+    DateTime::<Utc>::from_timestamp(seconds, subsec_nanos).map(|t| t.to_rfc3339())
+}
+
+// Leverage = (delta × spot) / premium: the dollar exposure per dollar of
+// premium. Negative for puts. None when an input is missing or the premium
+// is zero.
+fn compute_leverage(delta: Option<f64>, spot: Option<f64>, premium: Option<f64>) -> Option<f64> {
+    match (delta, spot, premium) {
+        (Some(delta), Some(spot), Some(premium)) if premium > 0.0 => Some(delta * spot / premium),
+        _ => None,
+    }
+}
+
+// Premium / strike, the option's cost relative to its strike (0.02 is 2%).
+// None without both, or for a zero strike.
+fn compute_premium_yield(premium: Option<f64>, strike: Option<f64>) -> Option<f64> {
+    match (premium, strike) {
+        (Some(premium), Some(strike)) if strike > 0.0 => Some(premium / strike),
+        _ => None,
+    }
+}
+
+// "ITM", "ATM" or "OTM" from the strike against spot: a call is in the money
+// below spot, a put above it, and a strike equal to spot (within
+// STRIKE_EPSILON) is at the money for either. None without a spot, strike or
+// known contract type.
+fn classify_moneyness(contract_type: Option<&str>, strike: Option<f64>, spot: Option<f64>) -> Option<&'static str> {
+    let (strike, spot) = (strike?, spot?);
+    if (strike - spot).abs() < STRIKE_EPSILON {
+        return Some("ATM");
+    }
+    let in_the_money = match contract_type? {
+        "call" => strike < spot,
+        "put" => strike > spot,
+        _ => return None,
+    };
+    Some(if in_the_money { "ITM" } else { "OTM" })
+}
+
+// Premium over intrinsic value, per share. Can dip below zero when the quote
+// lags the underlying.
+fn compute_extrinsic_value(
+    contract_type: Option<&str>,
+    strike: Option<f64>,
+    spot: Option<f64>,
+    premium: Option<f64>,
+) -> Option<f64> {
+    let (strike, spot, premium) = (strike?, spot?, premium?);
+    let intrinsic = match contract_type? {
+        "call" => (spot - strike).max(0.0),
+        "put" => (strike - spot).max(0.0),
+        _ => return None,
+    };
+    Some(premium - intrinsic)
+}
+
+// Thresholds for `assess_assignment_risk`: the last week of trading, and
+// extrinsic value small enough that exercising early gives up next to nothing.
+const ASSIGNMENT_RISK_DAYS: i64 = 5;
+const ASSIGNMENT_RISK_MAX_EXTRINSIC: f64 = 0.10;
+
+// Early-assignment risk for the short side. Out-of-the-money and ATM
+// contracts are "low"; an ITM contract is "high" when it is both within
+// ASSIGNMENT_RISK_DAYS of expiration and has at most
+// ASSIGNMENT_RISK_MAX_EXTRINSIC left, "medium" when only one holds, and
+// "low" otherwise. An unknown extrinsic value never counts as small.
+fn assess_assignment_risk(
+    moneyness: Option<&str>,
+    trading_days_to_expiration: Option<i64>,
+    extrinsic_value: Option<f64>,
+) -> Option<&'static str> {
+    if moneyness? != "ITM" {
+        return Some("low");
+    }
+    let near_expiration = trading_days_to_expiration? <= ASSIGNMENT_RISK_DAYS;
+    let little_extrinsic = extrinsic_value.is_some_and(|v| v <= ASSIGNMENT_RISK_MAX_EXTRINSIC);
+    Some(match (near_expiration, little_extrinsic) {
+        (true, true) => "high",
+        (true, false) | (false, true) => "medium",
+        (false, false) => "low",
+    })
+}
+
+// Probability of the underlying touching the strike before expiration,
+// approximated as twice the probability of finishing ITM, with |delta|
+// standing in for the latter. Clamped to [0, 1]: deep ITM contracts
+// (|delta| >= 0.5) saturate at 1.
+fn compute_probability_touch(delta: Option<f64>) -> Option<f64> {
+    delta
+        .filter(|delta| delta.is_finite())
+        .map(|delta| (2.0 * delta.abs()).clamp(0.0, 1.0))
+}
+
+// Rough liquidity: open interest plus the day's volume, discounted by the
+// relative bid-ask spread (a 10% spread keeps 90%). Contracts without a
+// two-sided quote score zero; None only when neither OI nor volume is known.
+fn liquidity_score(contract: &Value) -> Option<f64> {
+    let open_interest = contract["open_interest"].as_f64();
+    let volume = contract["day"]["volume"].as_f64();
+    if open_interest.is_none() && volume.is_none() {
+        return None;
+    }
+    let activity = open_interest.unwrap_or(0.0) + volume.unwrap_or(0.0);
+    let spread_rel = compute_spread(&contract["last_quote"]).1;
+    Some(spread_rel.map_or(0.0, |rel| activity * (1.0 - rel.clamp(0.0, 1.0))))
+}
+
+// Keeps the `n` highest-scoring contracts for each expiration, so no single
+// expiration dominates the result. Listing order is otherwise preserved.
+fn keep_most_liquid_per_expiration(snapshots: Vec<Value>, n: usize) -> Vec<Value> {
+    let mut by_expiration: BTreeMap<String, Vec<(usize, f64)>> = BTreeMap::new();
+    for (index, contract) in snapshots.iter().enumerate() {
+        let expiration = contract["details"]["expiration_date"].as_str().unwrap_or("").to_string();
+        let score = liquidity_score(contract).unwrap_or(f64::NEG_INFINITY);
+        by_expiration.entry(expiration).or_default().push((index, score));
+    }
+    let mut keep: BTreeSet<usize> = BTreeSet::new();
+    for mut scored in by_expiration.into_values() {
+        scored.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        keep.extend(scored.into_iter().take(n).map(|(index, _)| index));
+    }
+    snapshots
+        .into_iter()
+        .enumerate()
+        .filter(|(index, _)| keep.contains(index))
+        .map(|(_, contract)| contract)
+        .collect()
+}
+
+// The `n` earliest expiration dates among the fetched snapshots and the
+// formatted listing-only contracts.
+fn nearest_expirations(snapshots: &[Value], listing_only: &[Value], n: usize) -> BTreeSet<String> {
+    snapshots
+        .iter()
+        .filter_map(|contract| contract["details"]["expiration_date"].as_str())
+        .chain(listing_only.iter().filter_map(|contract| contract["expiration_date"].as_str()))
+        .map(|expiration| expiration.to_string())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .take(n)
+        .collect()
+}
+
+// Trading sessions per year, used to annualize DTE.
+const TRADING_DAYS_PER_YEAR: f64 = 252.0;
+
+// Floor for the time left on a contract expiring today: one trading hour,
+// so 0DTE computations stay finite instead of collapsing to zero.
+const MIN_YEARS_TO_EXPIRATION: f64 = 1.0 / (TRADING_DAYS_PER_YEAR * 6.5);
+
+// Time to expiration in years. A 0DTE contract is clamped to
+// MIN_YEARS_TO_EXPIRATION; an already expired one has no time left and
+// yields None.
+fn years_to_expiration(trading_days: i64) -> Option<f64> {
+    match trading_days {
+        days if days < 0 => None,
+        0 => Some(MIN_YEARS_TO_EXPIRATION),
+        days => Some(days as f64 / TRADING_DAYS_PER_YEAR),
+    }
+}
+
+// One-standard-deviation expected move of the underlying by expiration:
+// spot × IV × √T. None when an input is missing or the contract has expired.
+fn compute_expected_move(spot: Option<f64>, iv: Option<f64>, trading_days: Option<i64>) -> Option<f64> {
+    let years = years_to_expiration(trading_days?)?;
+    let expected_move = spot? * iv? * years.sqrt();
+    expected_move.is_finite().then_some(expected_move)
+}
+
+// Cost of one contract (price × multiplier). With `use_ask` the ask is used
+// as the price, falling back to the midpoint (and flagging it) when there's
+// no ask. Contracts without a multiplier are assumed to cover 100 shares.
+fn compute_contract_cost(contract: &Value, use_ask: bool) -> (Option<f64>, &'static str, bool) {
+    let multiplier = contract["details"]["shares_per_contract"]
+        .as_f64()
+        .unwrap_or(100.0);
+    let last_quote = &contract["last_quote"];
+    let midpoint = last_quote["midpoint"].as_f64();
+
+    if use_ask {
+        if let Some(ask) = last_quote["ask"].as_f64().filter(|&a| a > 0.0) {
+            return (Some(ask * multiplier), "ask", false);
+        }
+        return (midpoint.map(|m| m * multiplier), "mid", true);
+    }
+    (midpoint.map(|m| m * multiplier), "mid", false)
+}
+
+// Fallback summary built from the contracts listing alone, for contracts
+// whose snapshot fetch failed. Only the reference fields are known.
+fn format_listing_entry(entry: &Value) -> OptionContract {
+    OptionContract {
+        contract_type: entry["contract_type"].as_str().map(|s| s.to_string()),
+        data_source: "listing_only",
+        expiration_date: entry["expiration_date"].as_str().map(|s| s.to_string()),
+        strike_price: entry["strike_price"].as_f64().map(round_strike),
+        ticker: entry["ticker"].as_str().map(|s| s.to_string()),
+        ..OptionContract::default()
+    }
+}
+
+// Shares equivalent to one contract's delta: positive for calls (short that
+// many shares against a long call), negative for puts (buy them). None
+// without both a delta and a multiplier.
+fn hedge_shares(contract: &Value) -> Option<i64> {
+    let delta = contract["greeks"]["delta"].as_f64()?;
+    let multiplier = contract["details"]["shares_per_contract"].as_f64()?;
+    Some((delta * multiplier).round() as i64)
+}
+
+// Position-level greeks for one contract, in dollars. Each is None (null in
+// the response) when any of its inputs is missing.
+#[derive(Serialize, Clone, Copy, Debug, Default, PartialEq)]
+struct NotionalGreeks {
+    delta: Option<f64>,
+    gamma: Option<f64>,
+    theta: Option<f64>,
+    vega: Option<f64>,
+}
+
+// Dollar delta and gamma scale by the multiplier and spot (gamma per 1%
+// move), theta and vega by the multiplier only. `spot` is the one chosen by
+// resolve_spot, so these agree with `underlying_price`.
+fn notional_greeks(contract: &Value, spot: Option<f64>) -> NotionalGreeks {
+    let greeks = &contract["greeks"];
+    let multiplier = contract["details"]["shares_per_contract"].as_f64();
+
+    let dollar_delta = match (greeks["delta"].as_f64(), multiplier, spot) {
+        (Some(delta), Some(m), Some(spot)) => Some(delta * m * spot),
+        _ => None,
+    };
+    let dollar_gamma = match (greeks["gamma"].as_f64(), multiplier, spot) {
+        (Some(gamma), Some(m), Some(spot)) => Some(gamma * m * spot * spot / 100.0),
+        _ => None,
+    };
+    let dollar_theta = match (greeks["theta"].as_f64(), multiplier) {
+        (Some(theta), Some(m)) => Some(theta * m),
+        _ => None,
+    };
+    let dollar_vega = match (greeks["vega"].as_f64(), multiplier) {
+        (Some(vega), Some(m)) => Some(vega * m),
+        _ => None,
+    };
+
+    NotionalGreeks {
+        delta: dollar_delta,
+        gamma: dollar_gamma,
+        theta: dollar_theta,
+        vega: dollar_vega,
+    }
+}
+
+// Groups snapshots by expiration and strike and returns, for every strike
+// where both a call and a put were fetched, their summaries side by side with
+// the combined premium (straddle/strangle cost) and net delta. `formatted`
+// must be the formatted summaries of `snapshots`, in the same order.
+fn pair_contracts_by_strike(snapshots: &[Value], formatted: &[Value]) -> Vec<Value> {
+    let mut sides: BTreeMap<(String, String), (Option<usize>, Option<usize>)> = BTreeMap::new();
+    for (index, contract) in snapshots.iter().enumerate() {
+        let details = &contract["details"];
+        let (Some(expiration), Some(strike)) = (
+            details["expiration_date"].as_str(),
+            details["strike_price"].as_f64(),
+        ) else {
+            continue;
+        };
+        let entry = sides
+            .entry((expiration.to_string(), format!("{:.3}", strike)))
+            .or_default();
+        match details["contract_type"].as_str() {
+            Some("call") => entry.0 = Some(index),
+            Some("put") => entry.1 = Some(index),
+            _ => {}
+        }
+    }
+
+    sides
+        .into_iter()
+        .filter_map(|((expiration, _), sides)| match sides {
+            (Some(call), Some(put)) => {
+                let (call_raw, put_raw) = (&snapshots[call], &snapshots[put]);
+                let combined_premium = match (
+                    call_raw["last_quote"]["midpoint"].as_f64(),
+                    put_raw["last_quote"]["midpoint"].as_f64(),
+                ) {
+                    (Some(c), Some(p)) => Some(c + p),
+                    _ => None,
+                };
+                let net_delta = match (
+                    call_raw["greeks"]["delta"].as_f64(),
+                    put_raw["greeks"]["delta"].as_f64(),
+                ) {
+                    (Some(c), Some(p)) => Some(c + p),
+                    _ => None,
+                };
+                Some(json!({
+                    "expiration_date": expiration,
+                    "strike_price": call_raw["details"]["strike_price"].as_f64().map(round_strike),
+                    "call": formatted[call],
+                    "put": formatted[put],
+                    "combined_premium": combined_premium,
+                    "net_delta": net_delta,
+                }))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+// Prices a two-leg vertical at the nearest expiration where both strikes are
+// quoted. Net premium is long mid − short mid per share: positive is a debit,
+// negative a credit. A debit spread risks the debit to make width − debit; a
+// credit spread keeps the credit at best and loses width − credit at worst.
+// `formatted` must be the formatted summaries of `snapshots`, in the same
+// order.
+fn find_vertical(snapshots: &[Value], formatted: &[Value], long_strike: f64, short_strike: f64) -> Option<Value> {
+    let find_leg = |expiration: &str, strike: f64| {
+        snapshots.iter().position(|contract| {
+            let details = &contract["details"];
+            details["expiration_date"].as_str() == Some(expiration)
+                && details["strike_price"]
+                    .as_f64()
+                    .is_some_and(|listed| (listed - strike).abs() < STRIKE_EPSILON)
+                && contract["last_quote"]["midpoint"].as_f64().is_some()
+        })
+    };
+    let expirations: BTreeSet<&str> = snapshots
+        .iter()
+        .filter_map(|contract| contract["details"]["expiration_date"].as_str())
+        .collect();
+    let (expiration, long, short) = expirations.into_iter().find_map(|expiration| {
+        Some((expiration, find_leg(expiration, long_strike)?, find_leg(expiration, short_strike)?))
+    })?;
+
+    let net_premium = snapshots[long]["last_quote"]["midpoint"].as_f64()?
+        - snapshots[short]["last_quote"]["midpoint"].as_f64()?;
+    let width = (long_strike - short_strike).abs();
+    let (max_profit, max_loss) = if net_premium >= 0.0 {
+        (width - net_premium, net_premium)
+    } else {
+        (-net_premium, width + net_premium)
+    };
+    Some(json!({
+        "expiration_date": expiration,
+        "long": formatted[long],
+        "short": formatted[short],
+        "net_premium": net_premium,
+        "net_type": if net_premium >= 0.0 { "debit" } else { "credit" },
+        "width": width,
+        "max_profit": max_profit,
+        "max_loss": max_loss,
+    }))
+}
+
+// ATM implied volatility for each expiration, nearest first: the IV of the
+// contract whose strike is closest to spot, averaged over the call and put
+// when both sit at that strike. Expirations with no contract carrying a
+// parseable date, a strike, a spot and an IV are left out.
+fn atm_term_structure(snapshots: &[Value], fetched_spot: Option<f64>, today: NaiveDate) -> Vec<Value> {
+    let mut by_expiration: BTreeMap<NaiveDate, Vec<(f64, f64)>> = BTreeMap::new();
+    for contract in snapshots {
+        let expiration = contract["details"]["expiration_date"].as_str().and_then(parse_date);
+        let strike = contract["details"]["strike_price"].as_f64();
+        let spot = resolve_spot(contract, fetched_spot).0;
+        let iv = contract["implied_volatility"].as_f64();
+        if let (Some(expiration), Some(strike), Some(spot), Some(iv)) = (expiration, strike, spot, iv) {
+            by_expiration.entry(expiration).or_default().push(((strike - spot).abs(), iv));
+        }
+    }
+
+    by_expiration
+        .into_iter()
+        .map(|(expiration, contracts)| {
+            let nearest = contracts.iter().map(|(distance, _)| *distance).fold(f64::INFINITY, f64::min);
+            let atm_ivs: Vec<f64> = contracts
+                .iter()
+                .filter(|(distance, _)| *distance - nearest < STRIKE_EPSILON)
+                .map(|(_, iv)| *iv)
+                .collect();
+            json!({
+                "expiration": expiration.format("%Y-%m-%d").to_string(),
+                "dte": (expiration - today).num_days(),
+                "atm_iv": atm_ivs.iter().sum::<f64>() / atm_ivs.len() as f64,
+            })
+        })
+        .collect()
+}
+
+// From the strike pairs, takes the nearest expiration and the strike closest
+// to spot, and prices the straddle there. The straddle price relative to spot
+// is the market's implied expected move through that expiration.
+fn find_atm_straddle(pairs: &[Value], spot: f64) -> Option<Value> {
+    let nearest_expiration = pairs
+        .iter()
+        .filter(|pair| !pair["combined_premium"].is_null())
+        .filter_map(|pair| pair["expiration_date"].as_str())
+        .min()?;
+
+    let pair = pairs
+        .iter()
+        .filter(|pair| pair["expiration_date"].as_str() == Some(nearest_expiration))
+        .filter(|pair| !pair["combined_premium"].is_null())
+        .min_by(|a, b| {
+            let distance = |pair: &Value| (pair["strike_price"].as_f64().unwrap_or(f64::INFINITY) - spot).abs();
+            distance(a)
+                .partial_cmp(&distance(b))
+                .unwrap_or(std::cmp::Ordering::Equal)
+        })?;
+
+    let straddle_price = pair["combined_premium"].as_f64()?;
+    let implied_move_pct = if spot > 0.0 {
+        Some(straddle_price / spot * 100.0)
+    } else {
+        None
+    };
+
+    Some(json!({
+        "expiration_date": nearest_expiration,
+        "strike_price": pair["strike_price"],
+        "underlying_price": spot,
+        "straddle_price": straddle_price,
+        "implied_move": straddle_price,
+        "implied_move_pct": implied_move_pct,
+        "call": pair["call"],
+        "put": pair["put"],
+    }))
+}
+
+// Projects a contract onto a `select` tree that mirrors the response shape:
+// `true` keeps a field whole, a nested object selects within it, and
+// anything else drops it. Arrays apply the selection to each element.
+fn apply_selection(value: &Value, select: &Value) -> Value {
+    match (value, select) {
+        (_, Value::Bool(true)) => value.clone(),
+        (Value::Array(items), Value::Object(_)) => Value::Array(
+            items.iter().map(|item| apply_selection(item, select)).collect(),
+        ),
+        (Value::Object(object), Value::Object(fields)) => {
+            let mut selected = serde_json::Map::new();
+            for (field, field_select) in fields {
+                if let Some(field_value) = object.get(field) {
+                    if matches!(field_select, Value::Bool(true) | Value::Object(_)) {
+                        selected.insert(field.clone(), apply_selection(field_value, field_select));
+                    }
+                }
+            }
+            Value::Object(selected)
+        }
+        _ => Value::Null,
+    }
+}
+
+// Compact wire format: `header` lists every field name once, in alphabetical
+// order (the order the full objects serialize in), and each `data` row holds
+// one contract's values in that order. Fields a contract lacks are null.
+// Zipping `header` with a row gives back the full object.
+fn to_compact_format(contracts: &[Value]) -> Value {
+    let header: Vec<String> = contracts
+        .iter()
+        .filter_map(|contract| contract.as_object())
+        .flat_map(|object| object.keys().cloned())
+        .collect::<BTreeSet<String>>()
+        .into_iter()
+        .collect();
+    let data: Vec<Value> = contracts
+        .iter()
+        .map(|contract| {
+            Value::Array(
+                header
+                    .iter()
+                    .map(|field| contract.get(field).cloned().unwrap_or(Value::Null))
+                    .collect(),
+            )
+        })
+        .collect();
+
+    json!({ "header": header, "data": data })
+}
+
+// Keys each contract summary by its OCC ticker. Tickers are unique within a
+// listing, so no summary is lost.
+fn to_ticker_map(tickers: &[String], contracts: Vec<Value>) -> Value {
+    let map: serde_json::Map<String, Value> = tickers.iter().cloned().zip(contracts).collect();
+    Value::Object(map)
+}
+
+// Bare quotes for latency-sensitive pollers: the fetched contracts, in
+// listing order, with only the ticker, bid, ask and the quote's nanosecond
+// timestamp, taken straight from the snapshots.
+fn to_quote_only(snapshots: &[Value]) -> Value {
+    let quotes: Vec<Value> = snapshots
+        .iter()
+        .map(|contract| {
+            let last_quote = &contract["last_quote"];
+            json!({
+                "ticker": contract["details"]["ticker"],
+                "bid": last_quote["bid"],
+                "ask": last_quote["ask"],
+                "updated": last_quote["last_updated"],
+            })
+        })
+        .collect();
+    json!(quotes)
+}
+
+// Fields `sort_by` accepts: the contract's numeric fields, `expiration_date`,
+// and the `expiration_then_atm` grouping.
+const SORT_FIELDS: &[&str] = &[
+    "ask",
+    "ask_size",
+    "bid",
+    "bid_size",
+    "contract_cost",
+    "days_to_expiration",
+    "delta",
+    "expected_move",
+    "expiration_date",
+    "expiration_then_atm",
+    "extrinsic_value",
+    "gamma",
+    "hedge_shares",
+    "implied_volatility",
+    "last_trade_price",
+    "leverage",
+    "liquidity_score",
+    "oi_change",
+    "oi_change_pct",
+    "open_interest",
+    "premium",
+    "premium_yield",
+    "probability_touch",
+    "quote_updated_ns",
+    "spread_abs",
+    "spread_rel",
+    "strike_price",
+    "theta",
+    "trading_days_to_expiration",
+    "underlying_price",
+    "vega",
+    "volume",
+    "vwap",
+];
+
+// Sorts formatted contracts by one of their fields. Numeric fields are
+// compared by value (formatted strings such as "239.97%" are parsed back);
+// contracts missing the field always sort last.
+fn sort_contracts(contracts: &mut [Value], sort_by: &str, descending: bool, locale: NumberLocale) {
+    if sort_by == "expiration_then_atm" {
+        sort_expiration_then_atm(contracts, locale);
+        return;
+    }
+
+    let key = |contract: &Value| -> Option<f64> { numeric_field(contract, sort_by, locale) };
+
+    contracts.sort_by(|a, b| {
+        if sort_by == "expiration_date" {
+            let ordering = a[sort_by].as_str().cmp(&b[sort_by].as_str());
+            return if descending { ordering.reverse() } else { ordering };
+        }
+        compare_optional(key(a), key(b), descending)
+    });
+}
+
+// Groups by expiration (nearest first) and, within each expiration, orders
+// strikes outward from the underlying price. Contracts without a spot sort
+// last within their expiration.
+fn sort_expiration_then_atm(contracts: &mut [Value], locale: NumberLocale) {
+    let distance = |contract: &Value| -> Option<f64> {
+        let strike = numeric_field(contract, "strike_price", locale)?;
+        let spot = contract["underlying_price"].as_f64()?;
+        Some((strike - spot).abs())
+    };
+
+    contracts.sort_by(|a, b| {
+        a["expiration_date"]
+            .as_str()
+            .cmp(&b["expiration_date"].as_str())
+            .then_with(|| compare_optional(distance(a), distance(b), false))
+    });
+}
+
+// Reads a formatted field as a number; string fields such as "239.97%" are
+// parsed back.
+fn numeric_field(contract: &Value, field: &str, locale: NumberLocale) -> Option<f64> {
+    contract[field]
+        .as_str()
+        .and_then(|s| locale.parse_number(s.trim_end_matches('%')))
+        .or_else(|| contract[field].as_f64())
+}
+
+// Orders present values (ascending or descending) ahead of missing ones.
+fn compare_optional(a: Option<f64>, b: Option<f64>, descending: bool) -> std::cmp::Ordering {
+    match (a, b) {
+        (Some(x), Some(y)) => {
+            let ordering = x.partial_cmp(&y).unwrap_or(std::cmp::Ordering::Equal);
+            if descending {
+                ordering.reverse()
+            } else {
+                ordering
+            }
+        }
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    }
+}
+
+// Per-invocation settings that shape how a snapshot is formatted.
+#[derive(Clone, Copy, Debug)]
+struct FormatOptions {
+    precision: usize,
+    // Renders display numbers as localized strings when set.
+    locale: Option<NumberLocale>,
+    use_ask_for_cost: bool,
+    include_greeks: bool,
+    // Historical IV (decimal) that each contract's IV is compared against.
+    baseline_iv: Option<f64>,
+    // Spot from a dedicated underlying fetch, preferred over the price
+    // embedded in each snapshot.
+    fetched_spot: Option<f64>,
+    fetched_spot_updated_ns: Option<i64>,
+    // Spots older than this many seconds mark contracts `spot_stale`.
+    max_spot_age_secs: Option<i64>,
+    now_ns: i64,
+    today: NaiveDate,
+}
+
+// Relative difference above which the embedded and fetched spot prices are
+// reported as divergent.
+const SPOT_DIVERGENCE_THRESHOLD: f64 = 0.005;
+
+// The two spot prices of a contract whose embedded price disagreed with the
+// fetched one.
+#[derive(Serialize, Clone, Copy, Debug, PartialEq)]
+struct SpotDivergence {
+    fetched: f64,
+    snapshot: f64,
+}
+
+// Picks the spot price for a contract, preferring the dedicated fetch. When
+// both sources are present and differ by more than the threshold, both
+// values are returned so callers can see the embedded price was stale.
+fn resolve_spot(
+    contract: &Value,
+    fetched_spot: Option<f64>,
+) -> (Option<f64>, Option<&'static str>, Option<SpotDivergence>) {
+    let embedded_spot = contract["underlying_asset"]["price"].as_f64();
+    match (fetched_spot, embedded_spot) {
+        (Some(fetched), Some(embedded)) => {
+            let divergence = if fetched > 0.0 {
+                ((fetched - embedded) / fetched).abs()
+            } else {
+                0.0
+            };
+            let detail = (divergence > SPOT_DIVERGENCE_THRESHOLD)
+                .then_some(SpotDivergence { fetched, snapshot: embedded });
+            (Some(fetched), Some("spot_fetch"), detail)
+        }
+        (Some(fetched), None) => (Some(fetched), Some("spot_fetch"), None),
+        (None, Some(embedded)) => (Some(embedded), Some("snapshot"), None),
+        (None, None) => (None, None, None),
+    }
+}
+
+// Whether a snapshot carries everything `require_complete` asks for:
+// implied volatility, all four greeks, open interest, day volume and a
+// two-sided quote.
+fn has_complete_data(contract: &Value) -> bool {
+    let greeks = &contract["greeks"];
+    contract["implied_volatility"].is_number()
+        && ["delta", "gamma", "theta", "vega"].iter().all(|name| greeks[*name].is_number())
+        && contract["open_interest"].is_number()
+        && contract["day"]["volume"].is_number()
+        && compute_spread(&contract["last_quote"]).0.is_some()
+}
+
+// Whether the spot behind the spot-derived fields is older than
+// `max_spot_age_secs`. None when no threshold was given or the spot's
+// timestamp is unknown.
+fn is_spot_stale(contract: &Value, spot_source: Option<&str>, options: &FormatOptions) -> Option<bool> {
+    let max_age_secs = options.max_spot_age_secs?;
+    let updated_ns = match spot_source? {
+        "spot_fetch" => options.fetched_spot_updated_ns?,
+        _ => contract["underlying_asset"]["last_updated"].as_i64()?,
+    };
+    Some(options.now_ns.saturating_sub(updated_ns) > max_age_secs.saturating_mul(1_000_000_000))
+}
+
+// Returns the absolute bid-ask spread and the spread relative to the
+// midpoint. Both are None unless the quote is two-sided: a zero bid or ask
+// means nobody is quoting that side, and the "spread" against it would be
+// meaningless. The relative term is also None when the midpoint is zero.
+fn compute_spread(last_quote: &Value) -> (Option<f64>, Option<f64>) {
+    let (bid, ask) = match (last_quote["bid"].as_f64(), last_quote["ask"].as_f64()) {
+        (Some(bid), Some(ask)) if bid > 0.0 && ask > 0.0 => (bid, ask),
+        _ => return (None, None),
+    };
+
+    let spread_abs = ask - bid;
+    let midpoint = last_quote["midpoint"].as_f64().unwrap_or((bid + ask) / 2.0);
+    let spread_rel = if midpoint > 0.0 {
+        Some(spread_abs / midpoint)
+    } else {
+        None
+    };
+
+    (Some(spread_abs), spread_rel)
+}
+
+// The underlying's current IV is taken as the mean implied volatility of the
+// returned contracts.
+fn mean_implied_volatility(snapshots: &[Value]) -> Option<f64> {
+    let ivs: Vec<f64> = snapshots
+        .iter()
+        .filter_map(|contract| contract["implied_volatility"].as_f64())
+        .collect();
+    if ivs.is_empty() {
+        None
+    } else {
+        Some(ivs.iter().sum::<f64>() / ivs.len() as f64)
+    }
+}
+
+// IV rank = (current - min) / (max - min) * 100 over the supplied history.
+// Undefined when the history is flat.
+fn iv_rank(current_iv: f64, history: &[f64]) -> Option<f64> {
+    let min = history.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = history.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    if history.is_empty() || max <= min {
+        return None;
+    }
+    Some(((current_iv - min) / (max - min) * 100.0).clamp(0.0, 100.0))
+}
+
+// IV percentile = share of history observations strictly below the current
+// IV, as a percentage.
+fn iv_percentile(current_iv: f64, history: &[f64]) -> Option<f64> {
+    if history.is_empty() {
+        return None;
+    }
+    let below = history.iter().filter(|&&iv| iv < current_iv).count();
+    Some(below as f64 / history.len() as f64 * 100.0)
+}
+
+// API Gateway and Function URL events always carry `queryStringParameters`
+// and `headers`, often as null, next to an optional `body` that most invokers
+// send as stringified JSON and some as the object itself. Parameters are
+// merged from all three: the body overrides the query string, which
+// overrides headers.
+fn proxy_parameters(event_payload: &Value) -> Payload {
+    let body = match &event_payload["body"] {
+        Value::String(body) => serde_json::from_str(body).unwrap_or(Value::Null),
+        body => body.clone(),
+    };
+    let mut merged = serde_json::Map::new();
+    for source in [&event_payload["headers"], &event_payload["queryStringParameters"], &body] {
+        if let Value::Object(fields) = source {
+            for (field, value) in fields.iter().filter(|(_, value)| !value.is_null()) {
+                merged.insert(field.clone(), value.clone());
+            }
+        }
+    }
+    serde_json::from_value(Value::Object(merged)).unwrap_or_default()
+}
+
+// Flags may arrive as JSON booleans or, from headers and query strings, as
+// "true"/"false" strings.
+fn parse_flag(value: &Value) -> Option<bool> {
+    match value {
+        Value::Bool(b) => Some(*b),
+        Value::String(s) => match s.trim().to_lowercase().as_str() {
+            "true" | "1" | "yes" => Some(true),
+            "false" | "0" | "no" => Some(false),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn deserialize_flag<'de, D>(deserializer: D) -> Result<Option<bool>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = Option::<Value>::deserialize(deserializer)?;
+    Ok(value.as_ref().and_then(parse_flag))
+}
+
+fn deserialize_numeric<'de, D, T>(deserializer: D) -> Result<Option<NumericParam<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: FromStr,
+{
+    let value = Option::<Value>::deserialize(deserializer)?;
+    Ok(value.as_ref().map(NumericParam::from_value))
+}
+
+// NYSE closures announced outside the regular holiday rules, such as
+// national days of mourning.
+const US_MARKET_SPECIAL_CLOSURES: &[&str] = &["2025-01-09"];
+
+// NYSE full-day closures, computed from the exchange's holiday rules so the
+// calendar doesn't run out: fixed-date holidays falling on a Saturday are
+// observed the Friday before and on a Sunday the Monday after, except New
+// Year's Day, which is not observed on the preceding Friday.
+fn is_market_holiday(date: NaiveDate) -> bool {
+    let year = date.year();
+    let nth_monday = |month: u32, n: u8| NaiveDate::from_weekday_of_month_opt(year, month, Weekday::Mon, n);
+    let observed = |month: u32, day: u32| {
+        let holiday = NaiveDate::from_ymd_opt(year, month, day)?;
+        match holiday.weekday() {
+            Weekday::Sat => holiday.pred_opt(),
+            Weekday::Sun => holiday.succ_opt(),
+            _ => Some(holiday),
+        }
+    };
+    let holidays = [
+        NaiveDate::from_ymd_opt(year, 1, 1)
+            .filter(|day| day.weekday() != Weekday::Sat)
+            .and_then(|day| if day.weekday() == Weekday::Sun { day.succ_opt() } else { Some(day) }),
+        nth_monday(1, 3),
+        nth_monday(2, 3),
+        easter_sunday(year).map(|easter| easter - Duration::days(2)),
+        last_weekday_of_month(year, 5, Weekday::Mon),
+        if year >= 2022 { observed(6, 19) } else { None },
+        observed(7, 4),
+        nth_monday(9, 1),
+        NaiveDate::from_weekday_of_month_opt(year, 11, Weekday::Thu, 4),
+        observed(12, 25),
+    ];
+    holidays.contains(&Some(date)) || US_MARKET_SPECIAL_CLOSURES.contains(&date.format("%Y-%m-%d").to_string().as_str())
+}
+
+// NYSE early (1 p.m. ET) closes: the day after Thanksgiving, and July 3 and
+// December 24 when they fall on Monday to Thursday (on a Friday they are the
+// observed holiday).
+fn is_early_close(date: NaiveDate) -> bool {
+    let monday_to_thursday = matches!(date.weekday(), Weekday::Mon | Weekday::Tue | Weekday::Wed | Weekday::Thu);
+    let day_after_thanksgiving = NaiveDate::from_weekday_of_month_opt(date.year(), 11, Weekday::Thu, 4)
+        .and_then(|thanksgiving| thanksgiving.succ_opt());
+    Some(date) == day_after_thanksgiving
+        || (monday_to_thursday && matches!((date.month(), date.day()), (7, 3) | (12, 24)))
+}
+
+// Western Easter, by the anonymous Gregorian algorithm.
+fn easter_sunday(year: i32) -> Option<NaiveDate> {
+    let a = year % 19;
+    let b = year / 100;
+    let c = year % 100;
+    let d = b / 4;
+    let e = b % 4;
+    let f = (b + 8) / 25;
+    let g = (b - f + 1) / 3;
+    let h = (19 * a + b - d - g + 15) % 30;
+    let i = c / 4;
+    let k = c % 4;
+    let l = (32 + 2 * e + 2 * i - h - k) % 7;
+    let m = (a + 11 * h + 22 * l) / 451;
+    let month = (h + l - 7 * m + 114) / 31;
+    let day = (h + l - 7 * m + 114) % 31 + 1;
+    NaiveDate::from_ymd_opt(year, month as u32, day as u32)
+}
+
+fn last_weekday_of_month(year: i32, month: u32, weekday: Weekday) -> Option<NaiveDate> {
+    (1..=5)
+        .rev()
+        .find_map(|n| NaiveDate::from_weekday_of_month_opt(year, month, weekday, n))
+}
+
+fn is_trading_day(date: NaiveDate) -> bool {
+    !matches!(date.weekday(), Weekday::Sat | Weekday::Sun) && !is_market_holiday(date)
+}
+
+// Trading sessions after `from` up to and including `to`, so a contract
+// expiring at today's close is 0 and one expiring after a holiday weekend
+// only counts the sessions actually left. Negative for past dates.
+fn trading_days_between(from: NaiveDate, to: NaiveDate) -> i64 {
+    let (start, end, sign) = if to >= from { (from, to, 1) } else { (to, from, -1) };
+    let count = start
+        .iter_days()
+        .skip(1)
+        .take_while(|date| *date <= end)
+        .filter(|date| is_trading_day(*date))
+        .count() as i64;
+    count * sign
+}
+
+// Standard monthlies expire on the third Friday, moving to the Thursday
+// before when that Friday is a market holiday (e.g. Good Friday).
+fn is_monthly_expiration(date: NaiveDate) -> bool {
+    let Some(third_friday) = NaiveDate::from_weekday_of_month_opt(date.year(), date.month(), Weekday::Fri, 3) else {
+        return false;
+    };
+    let expiration = if is_market_holiday(third_friday) {
+        third_friday - Duration::days(1)
+    } else {
+        third_friday
+    };
+    date == expiration
+}
+
+// Quarterlies expire on the last trading day of March, June, September and
+// December; everything else that isn't a standard monthly is a weekly.
+fn expiration_class(date: NaiveDate) -> &'static str {
+    if is_quarterly_expiration(date) {
+        "quarterly"
+    } else if is_monthly_expiration(date) {
+        "monthly"
+    } else {
+        "weekly"
+    }
+}
+
+fn is_quarterly_expiration(date: NaiveDate) -> bool {
+    if !matches!(date.month(), 3 | 6 | 9 | 12) || !is_trading_day(date) {
+        return false;
+    }
+    date.iter_days()
+        .skip(1)
+        .take_while(|next| next.month() == date.month())
+        .all(|next| !is_trading_day(next))
+}
+
+// Percentage inputs must lie within 0–100. Out-of-range values are clamped
+// with a warning rather than producing a filter that matches nothing (or
+// everything); unparseable values are ignored, also with a warning.
+fn validate_pct(name: &str, raw: Option<&str>, warnings: &mut Vec<String>) -> Option<f64> {
+    let raw = raw?;
+    let Ok(value) = raw.trim().parse::<f64>() else {
+        warnings.push(format!("Ignored {}: {:?} is not a number", name, raw));
+        return None;
+    };
+    if !value.is_finite() {
+        warnings.push(format!("Ignored {}: {} is not a finite number", name, raw));
+        return None;
+    }
+    let clamped = value.clamp(0.0, 100.0);
+    if clamped != value {
+        warnings.push(format!("Clamped {} from {} to {}", name, value, clamped));
+    }
+    Some(clamped)
+}
+
+// Accepts a JSON object, or its stringified form when it arrives through a
+// header or query string.
+fn parse_json_object(value: &Value) -> Option<Value> {
+    match value {
+        Value::Object(_) => Some(value.clone()),
+        Value::String(s) => serde_json::from_str::<Value>(s).ok().filter(|v| v.is_object()),
+        _ => None,
+    }
+}
+
+fn deserialize_json_object<'de, D>(deserializer: D) -> Result<Option<Value>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = Option::<Value>::deserialize(deserializer)?;
+    Ok(value.as_ref().and_then(parse_json_object))
+}
+
+// Accepts a JSON object of scalar values, or its stringified form when it
+// arrives through a header or query string.
+fn parse_string_map(value: &Value) -> Option<BTreeMap<String, String>> {
+    let object = match value {
+        Value::Object(object) => object.clone(),
+        Value::String(s) => match serde_json::from_str::<Value>(s) {
+            Ok(Value::Object(object)) => object,
+            _ => return None,
+        },
+        _ => return None,
+    };
+    Some(
+        object
+            .into_iter()
+            .filter_map(|(key, value)| match value {
+                Value::String(s) => Some((key, s)),
+                Value::Number(n) => Some((key, n.to_string())),
+                Value::Bool(b) => Some((key, b.to_string())),
+                _ => None,
+            })
+            .collect(),
+    )
+}
+
+fn deserialize_string_map<'de, D>(deserializer: D) -> Result<Option<BTreeMap<String, String>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = Option::<Value>::deserialize(deserializer)?;
+    Ok(value.as_ref().and_then(parse_string_map))
+}
+
+fn parse_date(value: &str) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(value.trim(), "%Y-%m-%d").ok()
+}
+
+// Accepts either a JSON array (`[150, 155]` or `["150", "155"]`) or a
+// comma-separated string (`"150,155"`), since headers and query strings can
+// only carry the latter.
+fn parse_number_list(value: &Value) -> Option<Vec<f64>> {
+    let numbers: Vec<f64> = match value {
+        Value::Array(items) => items
+            .iter()
+            .filter_map(|item| match item {
+                Value::Number(n) => n.as_f64(),
+                Value::String(s) => s.trim().parse().ok(),
+                _ => None,
+            })
+            .collect(),
+        Value::String(s) => s
+            .split(',')
+            .filter_map(|part| part.trim().parse().ok())
+            .collect(),
+        _ => Vec::new(),
+    };
+
+    if numbers.is_empty() {
+        None
+    } else {
+        Some(numbers)
+    }
+}
+
+fn deserialize_number_list<'de, D>(deserializer: D) -> Result<Option<Vec<f64>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let value = Option::<Value>::deserialize(deserializer)?;
+    Ok(value.as_ref().and_then(parse_number_list))
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Error> {
+    // JSON lines for CloudWatch; RUST_LOG sets the level (default: info).
+    // CloudWatch timestamps every line already.
+    tracing_subscriber::fmt()
+        .json()
+        .with_env_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")))
+        .without_time()
+        .init();
+    info!(configured = env_api_key().is_some(), "POLYGON_API_KEY");
+    run(service_fn(handle_event)).await
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use lambda_runtime::Context;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::{TcpListener, TcpStream};
+    use std::sync::Arc;
+
+    // One request as the mock server received it, with the path and query
+    // parameters percent-decoded.
+    #[derive(Clone, Debug)]
+    struct MockRequest {
+        method: String,
+        path: String,
+        query: HashMap<String, String>,
+        headers: HashMap<String, String>,
+        body: String,
+    }
+
+    type Route = Arc<dyn Fn(&MockRequest) -> Option<(u16, String)> + Send + Sync>;
+
+    // A local stand-in for Polygon, reached through POLYGON_BASE_URL. Each
+    // test routes its own underlying, so tests running in parallel (and the
+    // process-wide caches) never see each other's data. The first route that
+    // answers a request wins; anything unrouted is a 404. Every connection
+    // is served on its own thread, so a route may block to simulate a slow
+    // Polygon.
+    struct MockServer {
+        base_url: String,
+        routes: Mutex<Vec<Route>>,
+        requests: Mutex<Vec<MockRequest>>,
+    }
+
+    static MOCK_SERVER: OnceLock<MockServer> = OnceLock::new();
+
+    fn mock() -> &'static MockServer {
+        MOCK_SERVER.get_or_init(|| {
+            let listener = TcpListener::bind("127.0.0.1:0").expect("bind mock server");
+            let base_url = format!("http://{}", listener.local_addr().unwrap());
+            env::set_var("POLYGON_BASE_URL", &base_url);
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    std::thread::spawn(move || serve_connection(stream));
+                }
+            });
+            MockServer {
+                base_url,
+                routes: Mutex::new(Vec::new()),
+                requests: Mutex::new(Vec::new()),
+            }
+        })
+    }
+
+    impl MockServer {
+        fn route(&self, route: impl Fn(&MockRequest) -> Option<(u16, String)> + Send + Sync + 'static) {
+            self.routes.lock().unwrap().push(Arc::new(route));
+        }
+
+        // Requests received so far that `filter` picks out.
+        fn requests(&self, filter: impl Fn(&MockRequest) -> bool) -> Vec<MockRequest> {
+            self.requests.lock().unwrap().iter().filter(|r| filter(r)).cloned().collect()
+        }
+    }
+
+    fn serve_connection(stream: TcpStream) {
+        let Some(request) = read_request(&stream) else {
+            return;
+        };
+        let server = MOCK_SERVER.get().expect("mock server is initialized before use");
+        server.requests.lock().unwrap().push(request.clone());
+        let routes: Vec<Route> = server.routes.lock().unwrap().clone();
+        let (status, body) = routes
+            .iter()
+            .find_map(|route| route(&request))
+            .unwrap_or((404, json!({ "status": "NOT_FOUND", "message": "no mock route" }).to_string()));
+        let response = format!(
+            "HTTP/1.1 {} Mock\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+            status,
+            body.len(),
+            body
+        );
+        // The client may have given up on a deliberately slow route.
+        let _ = (&stream).write_all(response.as_bytes());
+    }
+
+    fn read_request(stream: &TcpStream) -> Option<MockRequest> {
+        let mut reader = BufReader::new(stream);
+        let mut request_line = String::new();
+        reader.read_line(&mut request_line).ok()?;
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next()?.to_string();
+        let target = parts.next()?.to_string();
+
+        let mut headers = HashMap::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).ok()?;
+            let line = line.trim_end();
+            if line.is_empty() {
+                break;
+            }
+            let (name, value) = line.split_once(':')?;
+            headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+        }
+        let length: usize = headers.get("content-length").and_then(|l| l.parse().ok()).unwrap_or(0);
+        let mut body = vec![0; length];
+        reader.read_exact(&mut body).ok()?;
+
+        let url = Url::parse(&format!("http://mock{}", target)).ok()?;
+        Some(MockRequest {
+            method,
+            path: urlencoding::decode(url.path()).ok()?.into_owned(),
+            query: url.query_pairs().into_owned().collect(),
+            headers,
+            body: String::from_utf8_lossy(&body).into_owned(),
+        })
+    }
+
+    fn date_in(days: i64) -> String {
+        (Local::now().date_naive() + Duration::days(days)).format("%Y-%m-%d").to_string()
+    }
+
+    fn occ_ticker(underlying: &str, contract_type: &str, strike: f64, expiration: &str) -> String {
+        let expiration = parse_date(expiration).expect("fixture expiration is a date");
+        format!(
+            "O:{}{}{}{:08}",
+            underlying,
+            expiration.format("%y%m%d"),
+            if contract_type == "put" { 'P' } else { 'C' },
+            (strike * 1000.0).round() as i64
+        )
+    }
+
+    // A fully populated snapshot; tests remove or change what they need.
+    fn snapshot(underlying: &str, contract_type: &str, strike: f64, expiration: &str) -> Value {
+        json!({
+            "details": {
+                "ticker": occ_ticker(underlying, contract_type, strike, expiration),
+                "contract_type": contract_type,
+                "strike_price": strike,
+                "expiration_date": expiration,
+                "shares_per_contract": 100,
+            },
+            "last_quote": {
+                "bid": 1.2,
+                "ask": 1.3,
+                "midpoint": 1.25,
+                "bid_size": 10,
+                "ask_size": 12,
+                "last_updated": 1_700_000_000_000_000_000i64,
+            },
+            "greeks": { "delta": 0.5, "gamma": 0.02, "theta": -0.05, "vega": 0.1 },
+            "implied_volatility": 0.3,
+            "open_interest": 1000,
+            "day": { "volume": 200, "vwap": 1.24 },
+            "last_trade": { "price": 1.26 },
+            "underlying_asset": { "ticker": underlying, "price": 150.0 },
+        })
+    }
+
+    fn ticker_of(snapshot: &Value) -> String {
+        snapshot["details"]["ticker"].as_str().unwrap().to_string()
+    }
+
+    // The listing entry Polygon's contracts endpoint returns for a snapshot.
+    fn listing_entry(snapshot: &Value) -> Value {
+        let details = &snapshot["details"];
+        json!({
+            "ticker": details["ticker"],
+            "contract_type": details["contract_type"],
+            "strike_price": details["strike_price"],
+            "expiration_date": details["expiration_date"],
+            "underlying_ticker": snapshot["underlying_asset"]["ticker"],
+        })
+    }
+
+    fn is_listing_request(request: &MockRequest, underlying: &str) -> bool {
+        request.path == "/v3/reference/options/contracts"
+            && request.query.get("underlying_ticker").map(String::as_str) == Some(underlying)
+    }
+
+    fn snapshot_path_prefix(underlying: &str) -> String {
+        format!("/v3/snapshot/options/{}/", underlying)
+    }
+
+    // Routes `underlying` to a single-page listing of `snapshots` (honoring
+    // the contract_type filter, as Polygon does) and serves each snapshot by
+    // its ticker.
+    fn serve_chain(underlying: &str, snapshots: &[Value]) {
+        let server = mock();
+        let listed = snapshots.to_vec();
+        let listing_underlying = underlying.to_string();
+        server.route(move |request| {
+            if !is_listing_request(request, &listing_underlying) {
+                return None;
+            }
+            let results: Vec<Value> = listed
+                .iter()
+                .filter(|snapshot| {
+                    request
+                        .query
+                        .get("contract_type")
+                        .is_none_or(|t| snapshot["details"]["contract_type"].as_str() == Some(t))
+                })
+                .map(listing_entry)
+                .collect();
+            Some((200, json!({ "status": "OK", "results": results }).to_string()))
+        });
+        let served = snapshots.to_vec();
+        let prefix = snapshot_path_prefix(underlying);
+        server.route(move |request| {
+            let ticker = request.path.strip_prefix(&prefix)?;
+            let snapshot = served.iter().find(|snapshot| snapshot["details"]["ticker"] == ticker)?;
+            Some((200, json!({ "status": "OK", "results": snapshot }).to_string()))
+        });
+    }
+
+    async fn invoke(event: Value) -> Value {
+        mock();
+        let resp = function_handler(LambdaEvent::new(event, Context::default()))
+            .await
+            .expect("handler succeeds");
+        serde_json::from_str(&resp.response).expect("response is JSON")
+    }
+
+    // A direct invocation; requests carry a test key unless they set one.
+    async fn query(mut payload: Value) -> Value {
+        if payload.get("api_key").is_none() {
+            payload["api_key"] = json!("test-key");
+        }
+        invoke(payload).await
+    }
+
+    fn contracts(body: &Value) -> &Vec<Value> {
+        body["option_contracts"].as_array().expect("option_contracts is an array")
+    }
+
+    #[test]
+    fn fetch_budget_is_shared_until_spent() {
+        let budget = FetchBudget::new(4, 5);
+
+        assert_eq!(budget.take_fetches(3), 3);
+        assert_eq!(budget.take_fetches(3), 2);
+        assert_eq!(budget.take_fetches(1), 0);
+        assert_eq!(budget.semaphore.available_permits(), 4);
+    }
+
+    #[tokio::test]
+    async fn watchlist_tickers_share_the_fetch_budget() {
+        let expiration = date_in(8);
+        for underlying in ["HBGA", "HBGB"] {
+            let chain: Vec<Value> =
+                [10.0, 11.0, 12.0].iter().map(|&strike| snapshot(underlying, "call", strike, &expiration)).collect();
+            serve_chain(underlying, &chain);
+        }
+        let budget = FetchBudget::new(2, 4);
+        let payload: Payload =
+            serde_json::from_value(json!({ "ticker_symbol": "HBGA,HBGB", "api_key": "test-key" })).unwrap();
+
+        let resp = handle_watchlist(payload, "req".to_string(), None, &budget).await.unwrap();
+        let body: Value = serde_json::from_str(&resp.response).unwrap();
+
+        let returned: usize = ["HBGA", "HBGB"]
+            .iter()
+            .map(|underlying| body["option_contracts"][underlying].as_array().unwrap().len())
+            .sum();
+        assert_eq!(returned, 4);
+        let truncated: u64 = ["HBGA", "HBGB"]
+            .iter()
+            .map(|underlying| body["metadata"][underlying]["truncated_count"].as_u64().unwrap())
+            .sum();
+        assert_eq!(truncated, 2);
+    }
+
+    #[tokio::test]
+    async fn watchlist_snapshot_fetches_stay_within_max_concurrency() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let peak = Arc::new(AtomicUsize::new(0));
+        let expiration = date_in(10);
+        for underlying in ["HPKA", "HPKB"] {
+            let chain: Vec<Value> = [30.0, 31.0, 32.0, 33.0]
+                .iter()
+                .map(|&strike| snapshot(underlying, "call", strike, &expiration))
+                .collect();
+            // Registered ahead of serve_chain's snapshot route, so it sees
+            // every snapshot request and holds each one open for a while.
+            let (in_flight, peak, prefix) = (in_flight.clone(), peak.clone(), snapshot_path_prefix(underlying));
+            mock().route(move |request| {
+                if request.path.starts_with(&prefix) {
+                    let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(StdDuration::from_millis(30));
+                    in_flight.fetch_sub(1, Ordering::SeqCst);
+                }
+                None
+            });
+            serve_chain(underlying, &chain);
+        }
+
+        let body = query(json!({ "ticker_symbol": "HPKA,HPKB", "max_concurrency": "2" })).await;
+
+        for underlying in ["HPKA", "HPKB"] {
+            assert_eq!(body["option_contracts"][underlying].as_array().unwrap().len(), 4);
+        }
+        assert!(peak.load(Ordering::SeqCst) <= 2, "peak in flight was {}", peak.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn holiday_rules_match_the_published_calendar() {
+        // The NYSE's published 2024–2027 calendar.
+        let holidays = [
+            "2024-01-01", "2024-01-15", "2024-02-19", "2024-03-29", "2024-05-27", "2024-06-19",
+            "2024-07-04", "2024-09-02", "2024-11-28", "2024-12-25",
+            "2025-01-01", "2025-01-09", "2025-01-20", "2025-02-17", "2025-04-18", "2025-05-26",
+            "2025-06-19", "2025-07-04", "2025-09-01", "2025-11-27", "2025-12-25",
+            "2026-01-01", "2026-01-19", "2026-02-16", "2026-04-03", "2026-05-25", "2026-06-19",
+            "2026-07-03", "2026-09-07", "2026-11-26", "2026-12-25",
+            "2027-01-01", "2027-01-18", "2027-02-15", "2027-03-26", "2027-05-31", "2027-06-18",
+            "2027-07-05", "2027-09-06", "2027-11-25", "2027-12-24",
+        ];
+        let early_closes = [
+            "2024-07-03", "2024-11-29", "2024-12-24",
+            "2025-07-03", "2025-11-28", "2025-12-24",
+            "2026-11-27", "2026-12-24",
+            "2027-11-26",
+        ];
+
+        let first = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let last = NaiveDate::from_ymd_opt(2027, 12, 31).unwrap();
+        for date in first.iter_days().take_while(|date| *date <= last) {
+            let formatted = date.format("%Y-%m-%d").to_string();
+            assert_eq!(is_market_holiday(date), holidays.contains(&formatted.as_str()), "{}", formatted);
+            assert_eq!(is_early_close(date), early_closes.contains(&formatted.as_str()), "{}", formatted);
+        }
+    }
+
+    #[test]
+    fn classifies_known_expirations() {
+        let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+
+        assert_eq!(expiration_class(date("2025-01-10")), "weekly");
+        assert_eq!(expiration_class(date("2025-01-17")), "monthly");
+        assert_eq!(expiration_class(date("2025-03-31")), "quarterly");
+        // Good Friday is the third Friday, so the monthly moves to Thursday.
+        assert_eq!(expiration_class(date("2025-04-17")), "monthly");
+        // Past the end of any published calendar.
+        assert_eq!(expiration_class(date("2030-04-18")), "monthly");
+        assert_eq!(expiration_class(date("2030-05-17")), "monthly");
+        assert_eq!(expiration_class(date("2030-05-24")), "weekly");
+        // New Year's Day 2028 is a Saturday and isn't observed on the 31st.
+        assert_eq!(expiration_class(date("2027-12-31")), "quarterly");
+        // Christmas 2032 is a Saturday, observed on the Friday before.
+        assert!(is_market_holiday(date("2032-12-24")));
+        assert_eq!(expiration_class(date("2032-12-31")), "quarterly");
+    }
+
+    #[test]
+    fn ttl_cache_evicts_the_oldest_entry_at_its_limit() {
+        let cache = TtlCache::new(2);
+        cache.insert("a".to_string(), 1);
+        std::thread::sleep(StdDuration::from_millis(2));
+        cache.insert("b".to_string(), 2);
+        cache.insert("a".to_string(), 3);
+        cache.insert("c".to_string(), 4);
+
+        let hour = StdDuration::from_secs(3600);
+        assert!(cache.get("b", hour).is_none());
+        assert_eq!(cache.get("a", hour).map(|(_, value)| value), Some(3));
+        assert_eq!(cache.get("c", hour).map(|(_, value)| value), Some(4));
+        std::thread::sleep(StdDuration::from_millis(2));
+        assert!(cache.get("c", StdDuration::from_millis(1)).is_none());
+    }
+
+    #[tokio::test]
+    async fn formats_contracts_from_the_listing_and_snapshots() {
+        let expiration = date_in(10);
+        let call = snapshot("HFMT", "call", 150.0, &expiration);
+        serve_chain("HFMT", std::slice::from_ref(&call));
+
+        let body = query(json!({ "ticker_symbol": "hfmt" })).await;
+
+        let contract = &contracts(&body)[0];
+        assert_eq!(contracts(&body).len(), 1);
+        assert_eq!(contract["ticker"], json!(ticker_of(&call)));
+        assert_eq!(contract["contract_type"], "call");
+        assert_eq!(contract["expiration_date"], json!(expiration));
+        assert_eq!(contract["days_to_expiration"], 10);
+        assert_eq!(contract["strike_price"], 150.0);
+        assert_eq!(contract["bid"], 1.2);
+        assert_eq!(contract["ask"], 1.3);
+        assert_eq!(contract["premium"], 1.25);
+        assert_eq!(contract["contract_cost"], 125.0);
+        assert_eq!(contract["spread_abs"], 0.1);
+        assert_eq!(contract["implied_volatility"], 0.3);
+        assert_eq!(contract["open_interest"], 1000);
+        assert_eq!(contract["underlying_price"], 150.0);
+        assert_eq!(contract["moneyness"], "ATM");
+        assert_eq!(contract["data_source"], "snapshot");
+        assert_eq!(body["metadata"]["api_calls_made"], 2);
+        assert_eq!(body["errors"], json!([]));
+
+        let listing = mock().requests(|r| is_listing_request(r, "HFMT"));
+        assert_eq!(listing[0].method, "GET");
+        assert_eq!(listing[0].query["apiKey"], "test-key");
+        assert_eq!(listing[0].query["contract_type"], "call");
+        assert_eq!(listing[0].query["limit"], "10");
+    }
+
+    #[tokio::test]
+    async fn omits_fields_the_snapshot_lacks() {
+        let mut sparse = snapshot("HSPR", "put", 95.0, &date_in(5));
+        let fields = sparse.as_object_mut().unwrap();
+        for field in ["last_quote", "greeks", "implied_volatility", "underlying_asset", "day"] {
+            fields.remove(field);
+        }
+        serve_chain("HSPR", std::slice::from_ref(&sparse));
+
+        let body = query(json!({ "ticker_symbol": "HSPR", "contract_type": "put" })).await;
+
+        let contract = contracts(&body)[0].as_object().unwrap();
+        assert_eq!(contract["ticker"], json!(ticker_of(&sparse)));
+        assert_eq!(contract["strike_price"], 95.0);
+        assert_eq!(contract["open_interest"], 1000);
+        for missing in [
+            "bid",
+            "ask",
+            "premium",
+            "contract_cost",
+            "spread_abs",
+            "spread_rel",
+            "implied_volatility",
+            "underlying_price",
+            "moneyness",
+            "leverage",
+            "expected_move",
+            "volume",
+        ] {
+            assert!(!contract.contains_key(missing), "{} should be omitted", missing);
+        }
+    }
+
+    #[tokio::test]
+    async fn reports_a_snapshot_error_status() {
+        let contract = snapshot("HERR", "call", 20.0, &date_in(3));
+        let ticker = ticker_of(&contract);
+        let server = mock();
+        // Registered ahead of serve_chain's snapshot route, so it answers first.
+        let failing = ticker.clone();
+        server.route(move |request| {
+            (request.path == format!("{}{}", snapshot_path_prefix("HERR"), failing)).then(|| {
+                (403, json!({ "status": "NOT_AUTHORIZED", "message": "Not entitled" }).to_string())
+            })
+        });
+        serve_chain("HERR", &[contract]);
+
+        let body = query(json!({ "ticker_symbol": "HERR" })).await;
+
+        assert_eq!(contracts(&body).len(), 0);
+        assert_eq!(body["errors"], json!([{ "ticker": ticker, "status": 403, "message": "Not entitled" }]));
+        assert!(body["warnings"][0].as_str().unwrap().contains("status 403"));
+    }
+
+    #[tokio::test]
+    async fn serves_cached_responses_no_older_than_max_stale_secs() {
+        serve_chain("HSTL", &[snapshot("HSTL", "call", 50.0, &date_in(6))]);
+        let snapshot_fetches = || mock().requests(|r| r.path.starts_with(&snapshot_path_prefix("HSTL"))).len();
+
+        let fresh = query(json!({ "ticker_symbol": "HSTL", "max_stale_secs": "20" })).await;
+        assert!(fresh["metadata"]["cache"].is_null());
+        assert_eq!(snapshot_fetches(), 1);
+
+        // Age the stored entry by 15 seconds.
+        for (key, (stored_at, _)) in response_cache().entries.lock().unwrap().iter_mut() {
+            if key.contains("\"HSTL\"") {
+                *stored_at -= StdDuration::from_secs(15);
+            }
+        }
+
+        let cached = query(json!({ "ticker_symbol": "HSTL", "max_stale_secs": "20" })).await;
+        assert_eq!(cached["metadata"]["cache"], json!({ "hit": true, "age_secs": 15 }));
+        assert_eq!(contracts(&cached), contracts(&fresh));
+        assert_eq!(snapshot_fetches(), 1);
+
+        let refetched = query(json!({ "ticker_symbol": "HSTL", "max_stale_secs": "10" })).await;
+        assert!(refetched["metadata"]["cache"].is_null());
+        assert_eq!(snapshot_fetches(), 2);
+
+        // Another API key never sees this key's body.
+        let other_key = query(json!({ "ticker_symbol": "HSTL", "max_stale_secs": "20", "api_key": "other-key" })).await;
+        assert!(other_key["metadata"]["cache"].is_null());
+        assert_eq!(snapshot_fetches(), 3);
+    }
+
+    // Each thread runs its own runtime, as overlapping warm invocations would.
+    #[test]
+    fn concurrent_invocations_share_the_response_cache() {
+        for underlying in ["HCCA", "HCCB"] {
+            serve_chain(underlying, &[snapshot(underlying, "call", 40.0, &date_in(9))]);
+        }
+
+        let bodies: Vec<Value> = std::thread::scope(|scope| {
+            let calls: Vec<_> = (0..32)
+                .map(|i| {
+                    let underlying = if i % 2 == 0 { "HCCA" } else { "HCCB" };
+                    scope.spawn(move || {
+                        tokio::runtime::Builder::new_current_thread()
+                            .enable_all()
+                            .build()
+                            .unwrap()
+                            .block_on(query(json!({ "ticker_symbol": underlying, "max_stale_secs": "60" })))
+                    })
+                })
+                .collect();
+            calls.into_iter().map(|call| call.join().expect("no invocation panics")).collect()
+        });
+
+        for underlying in ["HCCA", "HCCB"] {
+            let bodies: Vec<&Value> =
+                bodies.iter().filter(|body| contracts(body)[0]["ticker"].as_str().unwrap().contains(underlying)).collect();
+            assert_eq!(bodies.len(), 16);
+            // Every miss fetched the one snapshot, and every hit fetched nothing.
+            let misses = bodies.iter().filter(|body| body["metadata"]["cache"].is_null()).count();
+            assert!(misses >= 1);
+            assert_eq!(
+                mock().requests(|r| r.path.starts_with(&snapshot_path_prefix(underlying))).len(),
+                misses
+            );
+            for body in &bodies {
+                assert_eq!(contracts(body), contracts(bodies[0]));
+                let hit = body["metadata"]["cache"]["hit"] == true;
+                assert_eq!(body["metadata"]["api_calls_made"] == 0, hit);
+            }
+            let keys = response_cache()
+                .entries
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|key| key.contains(&format!("\"{}\"", underlying)))
+                .count();
+            assert_eq!(keys, 1);
+        }
+    }
+
+    #[tokio::test]
+    async fn does_not_cache_responses_with_fetch_errors() {
+        let contract = snapshot("HNCF", "call", 20.0, &date_in(3));
+        let failing = format!("{}{}", snapshot_path_prefix("HNCF"), ticker_of(&contract));
+        mock().route(move |request| {
+            (request.path == failing).then(|| (403, json!({ "status": "NOT_AUTHORIZED" }).to_string()))
+        });
+        serve_chain("HNCF", &[contract]);
+
+        for _ in 0..2 {
+            let body = query(json!({ "ticker_symbol": "HNCF", "max_stale_secs": "60" })).await;
+            assert!(body["metadata"]["cache"].is_null());
+            assert_eq!(body["errors"].as_array().unwrap().len(), 1);
+        }
+        assert_eq!(mock().requests(|r| r.path.starts_with(&snapshot_path_prefix("HNCF"))).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn api_calls_made_counts_every_request_served() {
+        let chain = [snapshot("HCNT", "call", 70.0, &date_in(5)), snapshot("HCNT", "call", 75.0, &date_in(5))];
+        // The first request for one snapshot fails, so it is retried.
+        let flaky = format!("{}{}", snapshot_path_prefix("HCNT"), ticker_of(&chain[0]));
+        let failed_once = Arc::new(AtomicUsize::new(0));
+        mock().route(move |request| {
+            (request.path == flaky && failed_once.fetch_add(1, Ordering::SeqCst) == 0)
+                .then(|| (500, json!({ "status": "ERROR" }).to_string()))
+        });
+        mock().route(|request| {
+            (request.path == "/v2/last/trade/HCNT")
+                .then(|| (200, json!({ "status": "OK", "results": { "p": 72.0, "t": 1 } }).to_string()))
+        });
+        serve_chain("HCNT", &chain);
+
+        let body = query(json!({ "ticker_symbol": "HCNT", "fetch_spot": true, "retry_base_delay_ms": "1" })).await;
+
+        assert_eq!(contracts(&body).len(), 2);
+        let served = mock().requests(|r| is_listing_request(r, "HCNT") || r.path.contains("HCNT"));
+        // One listing page, the spot, two snapshots and the retry.
+        assert_eq!(served.len(), 5);
+        assert_eq!(body["metadata"]["api_calls_made"], served.len());
+    }
+
+    #[tokio::test]
+    async fn a_failed_listing_returns_no_contracts() {
+        mock().route(|request| {
+            is_listing_request(request, "HLST")
+                .then(|| (401, json!({ "status": "ERROR", "message": "Unknown API Key" }).to_string()))
+        });
+
+        let body = query(json!({ "ticker_symbol": "HLST" })).await;
+
+        assert_eq!(contracts(&body).len(), 0);
+        assert_eq!(body["metadata"]["api_calls_made"], 1);
+        assert!(mock().requests(|r| r.path.starts_with(&snapshot_path_prefix("HLST"))).is_empty());
+    }
+
+    #[tokio::test]
+    async fn returns_only_the_listed_strikes() {
+        let expiration = date_in(12);
+        let chain: Vec<Value> = [145.0, 150.0, 155.0, 160.0]
+            .iter()
+            .map(|&strike| snapshot("HSTK", "call", strike, &expiration))
+            .collect();
+        serve_chain("HSTK", &chain);
+
+        // 159.9999 matches the listed 160 within STRIKE_EPSILON.
+        let body = query(json!({ "ticker_symbol": "HSTK", "strikes": [150, 159.9999] })).await;
+
+        let strikes: Vec<f64> = contracts(&body).iter().map(|c| c["strike_price"].as_f64().unwrap()).collect();
+        assert_eq!(strikes, vec![150.0, 160.0]);
+        let listing = mock().requests(|r| is_listing_request(r, "HSTK"));
+        let gte: f64 = listing[0].query["strike_price.gte"].parse().unwrap();
+        let lte: f64 = listing[0].query["strike_price.lte"].parse().unwrap();
+        assert!((149.99..150.0).contains(&gte) && (160.0..160.01).contains(&lte));
+    }
+
+    #[tokio::test]
+    async fn delayed_tier_reads_snapshots_from_the_rest_host() {
+        assert_eq!(
+            snapshot_url(DEFAULT_API_HOST, "AAPL", "O:AAPL241018P00100000"),
+            "https://api.polygon.io/v3/snapshot/options/AAPL/O%3AAAPL241018P00100000"
+        );
+
+        let contract = snapshot("HTIER", "call", 40.0, &date_in(8));
+        serve_chain("HTIER", std::slice::from_ref(&contract));
+
+        let body = query(json!({ "ticker_symbol": "HTIER", "data_tier": "delayed" })).await;
+
+        assert_eq!(contracts(&body).len(), 1);
+        assert_eq!(body["metadata"]["data_delayed"], true);
+        let fetched = mock().requests(|r| r.path.starts_with(&snapshot_path_prefix("HTIER")));
+        assert_eq!(fetched[0].path, format!("/v3/snapshot/options/HTIER/{}", ticker_of(&contract)));
+    }
+
+    #[tokio::test]
+    async fn expiration_window_combines_min_dte_and_days_forward() {
+        serve_chain("HWIN", &[snapshot("HWIN", "call", 10.0, &date_in(7))]);
+
+        let body = query(json!({ "ticker_symbol": "HWIN", "min_dte": "5", "days_forward": 20 })).await;
+
+        assert_eq!(body["metadata"]["expiration_window"], json!({ "from": date_in(5), "to": date_in(20) }));
+        let listing = mock().requests(|r| is_listing_request(r, "HWIN"));
+        assert_eq!(listing[0].query["expiration_date.gte"], date_in(5));
+        assert_eq!(listing[0].query["expiration_date.lte"], date_in(20));
+    }
+
+    #[tokio::test]
+    async fn rejects_an_out_of_range_min_dte() {
+        for min_dte in ["99999999999999", "-1", "soon"] {
+            let body = query(json!({ "ticker_symbol": "HWIN", "min_dte": min_dte })).await;
+            assert_eq!(body["error"]["status_code"], 400);
+            assert_eq!(body["error"]["field"], "min_dte");
+        }
+    }
+
+    #[tokio::test]
+    async fn attaches_daily_history_from_the_aggregates_endpoint() {
+        let contract = snapshot("HHIST", "call", 75.0, &date_in(9));
+        let ticker = ticker_of(&contract);
+        serve_chain("HHIST", &[contract]);
+        let aggregates_prefix = format!("/v2/aggs/ticker/{}/range/1/day/", ticker);
+        mock().route(move |request| {
+            request.path.starts_with(&aggregates_prefix).then(|| {
+                let bars = json!([
+                    { "t": 1_717_977_600_000i64, "o": 1.0, "h": 1.4, "l": 0.9, "c": 1.3, "v": 120 },
+                    { "t": 1_718_064_000_000i64, "o": 1.3, "h": 1.5, "l": 1.2, "c": 1.25, "v": 80 },
+                ]);
+                (200, json!({ "status": "OK", "results": bars }).to_string())
+            })
+        });
+
+        let body = query(json!({ "ticker_symbol": "HHIST", "include_history": true, "history_days": "7" })).await;
+
+        assert_eq!(
+            contracts(&body)[0]["history"],
+            json!([["2024-06-10", 1.0, 1.4, 0.9, 1.3, 120], ["2024-06-11", 1.3, 1.5, 1.2, 1.25, 80]])
+        );
+        let aggregates = mock().requests(|r| r.path.starts_with(&format!("/v2/aggs/ticker/{}/", ticker)));
+        assert_eq!(
+            aggregates[0].path,
+            format!("/v2/aggs/ticker/{}/range/1/day/{}/{}", ticker, date_in(-7), date_in(0))
+        );
+        assert_eq!(body["metadata"]["api_calls_made"], 3);
+    }
+
+    #[tokio::test]
+    async fn rejects_an_out_of_range_history_days() {
+        for history_days in ["0", "1096", "99999999999999"] {
+            let body = query(json!({ "ticker_symbol": "HHIST", "include_history": true, "history_days": history_days })).await;
+            assert_eq!(body["error"]["field"], "history_days");
+        }
+    }
+
+    // The shape API Gateway sends: both parameter maps present but null.
+    fn gateway_event(body: Value) -> Value {
+        json!({
+            "resource": "/contracts",
+            "httpMethod": "POST",
+            "headers": null,
+            "queryStringParameters": null,
+            "requestContext": { "requestId": "gateway-request" },
+            "body": body,
+            "isBase64Encoded": false,
+        })
+    }
+
+    #[tokio::test]
+    async fn reads_a_stringified_body_next_to_null_parameter_maps() {
+        serve_chain("HBDYS", &[snapshot("HBDYS", "put", 30.0, &date_in(4))]);
+        let body = json!({ "ticker_symbol": "HBDYS", "api_key": "test-key", "contract_type": "put" }).to_string();
+
+        let response = invoke(gateway_event(json!(body))).await;
+
+        assert_eq!(contracts(&response).len(), 1);
+        assert_eq!(contracts(&response)[0]["contract_type"], "put");
+    }
+
+    #[tokio::test]
+    async fn reads_an_object_body_next_to_null_parameter_maps() {
+        serve_chain("HBDYO", &[snapshot("HBDYO", "put", 30.0, &date_in(4))]);
+
+        let response = invoke(gateway_event(
+            json!({ "ticker_symbol": "HBDYO", "api_key": "test-key", "contract_type": "put" }),
+        ))
+        .await;
+
+        assert_eq!(contracts(&response).len(), 1);
+        assert_eq!(contracts(&response)[0]["contract_type"], "put");
+    }
+
+    #[test]
+    fn body_overrides_query_string_overrides_headers() {
+        let payload = proxy_parameters(&json!({
+            "headers": { "ticker_symbol": "HDR", "tag": "from-header", "host": "example.com" },
+            "queryStringParameters": { "ticker_symbol": "QRY", "limit": "5" },
+            "body": "{\"ticker_symbol\": \"BODY\"}",
+        }));
+
+        assert_eq!(payload.ticker_symbol.as_deref(), Some("BODY"));
+        assert_eq!(payload.limit, Some(NumericParam::Value(5)));
+        assert_eq!(payload.tag.as_deref(), Some("from-header"));
+    }
+
+    // The first date after today falling on `weekday`.
+    fn next_weekday(weekday: Weekday) -> String {
+        let today = Local::now().date_naive();
+        let date = today.iter_days().skip(1).find(|date| date.weekday() == weekday).unwrap();
+        date.format("%Y-%m-%d").to_string()
+    }
+
+    #[tokio::test]
+    async fn keeps_only_friday_expirations() {
+        let friday = snapshot("HWKD", "call", 50.0, &next_weekday(Weekday::Fri));
+        let thursday = snapshot("HWKD", "call", 50.0, &next_weekday(Weekday::Thu));
+        let monday = snapshot("HWKD", "call", 50.0, &next_weekday(Weekday::Mon));
+        serve_chain("HWKD", &[thursday, friday.clone(), monday]);
+
+        for weekday in ["friday", "Fri", "4"] {
+            let body = query(json!({ "ticker_symbol": "HWKD", "expiration_weekday": weekday, "bypass_cache": true })).await;
+
+            let tickers: Vec<&Value> = contracts(&body).iter().map(|c| &c["ticker"]).collect();
+            assert_eq!(tickers, vec![&json!(ticker_of(&friday))], "expiration_weekday {:?}", weekday);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_an_unknown_expiration_weekday() {
+        for weekday in ["funday", "7"] {
+            let body = query(json!({ "ticker_symbol": "HWKD", "expiration_weekday": weekday })).await;
+            assert_eq!(body["error"]["field"], "expiration_weekday");
+        }
+    }
+
+    fn listing_entries(strikes: &[f64]) -> (HashMap<String, Value>, Vec<String>) {
+        let expiration = date_in(14);
+        let entries: HashMap<String, Value> = strikes
+            .iter()
+            .map(|&strike| {
+                let entry = listing_entry(&snapshot("GRID", "call", strike, &expiration));
+                (entry["ticker"].as_str().unwrap().to_string(), entry)
+            })
+            .collect();
+        let tickers = entries.keys().cloned().collect();
+        (entries, tickers)
+    }
+
+    #[test]
+    fn resolves_strike_offsets_on_a_known_grid() {
+        let (entries, tickers) = listing_entries(&[95.0, 97.5, 100.0, 102.5, 105.0]);
+
+        // 100 is the listed strike closest to 101; offsets past either end
+        // of the grid, however large, are skipped.
+        assert_eq!(
+            resolve_strike_offsets(&entries, &tickers, 101.0, &[-2, -1, 0, 1, 2, 3, -3, i64::MAX, i64::MIN]),
+            vec![95.0, 97.5, 100.0, 102.5, 105.0]
+        );
+        assert_eq!(resolve_strike_offsets(&entries, &tickers, 103.0, &[0, 1]), vec![102.5, 105.0]);
+        assert!(resolve_strike_offsets(&HashMap::new(), &[], 101.0, &[0]).is_empty());
+    }
+
+    #[tokio::test]
+    async fn fetches_the_strikes_at_the_requested_offsets() {
+        let expiration = date_in(11);
+        let chain: Vec<Value> = [90.0, 95.0, 100.0, 105.0, 110.0]
+            .iter()
+            .map(|&strike| snapshot("HOFS", "call", strike, &expiration))
+            .collect();
+        serve_chain("HOFS", &chain);
+        mock().route(|request| {
+            (request.path == "/v2/last/trade/HOFS")
+                .then(|| (200, json!({ "status": "OK", "results": { "p": 101.2, "t": 1 } }).to_string()))
+        });
+
+        let body = query(json!({ "ticker_symbol": "HOFS", "strike_offsets": [-1, 0, 1] })).await;
+
+        assert_eq!(body["metadata"]["resolved_offset_strikes"], json!([95.0, 100.0, 105.0]));
+        let strikes: Vec<f64> = contracts(&body).iter().map(|c| c["strike_price"].as_f64().unwrap()).collect();
+        assert_eq!(strikes, vec![95.0, 100.0, 105.0]);
+    }
+
+    #[tokio::test]
+    async fn quote_only_returns_just_the_quote_fields() {
+        let expiration = date_in(12);
+        serve_chain(
+            "HQUO",
+            &[snapshot("HQUO", "call", 100.0, &expiration), snapshot("HQUO", "put", 100.0, &expiration)],
+        );
+
+        let body = query(json!({
+            "ticker_symbol": "HQUO",
+            "output_format": "quote_only",
+            "fetch_spot": true,
+            "pair_by_strike": true,
+            "include_term_structure": true,
+        }))
+        .await;
+
+        let quotes = contracts(&body);
+        assert_eq!(quotes.len(), 2);
+        for quote in quotes {
+            let mut keys: Vec<&str> = quote.as_object().unwrap().keys().map(String::as_str).collect();
+            keys.sort_unstable();
+            assert_eq!(keys, ["ask", "bid", "ticker", "updated"]);
+            assert_eq!(quote["bid"], 1.2);
+            assert_eq!(quote["ask"], 1.3);
+        }
+        assert!(body.get("strike_pairs").is_none());
+        assert!(body.get("term_structure").is_none());
+        assert!(mock().requests(|r| r.path == "/v2/last/trade/HQUO").is_empty());
+    }
+
+    #[tokio::test]
+    async fn rejects_fractional_strike_offsets() {
+        let body = query(json!({ "ticker_symbol": "HOFS", "strike_offsets": [0, 1.5] })).await;
+        assert_eq!(body["error"]["field"], "strike_offsets");
+    }
+
+    #[tokio::test]
+    async fn rejects_a_request_without_any_api_key() {
+        // POLYGON_API_KEY is never set in tests.
+        assert!(env_api_key().is_none());
+
+        for ticker_symbol in ["HNOKEY", "HNOKA,HNOKB"] {
+            let body = invoke(json!({ "ticker_symbol": ticker_symbol })).await;
+
+            assert_eq!(body["error"]["status_code"], 400);
+            assert_eq!(body["error"]["field"], "api_key");
+            assert!(body["error"]["message"].as_str().unwrap().contains("POLYGON_API_KEY"));
+        }
+        assert!(mock().requests(|r| r.query.get("apiKey").is_some_and(|key| key == "YOUR_API_KEY")).is_empty());
+        assert!(mock().requests(|r| r.query.get("underlying_ticker").is_some_and(|t| t.starts_with("HNOK"))).is_empty());
+    }
+
+    #[test]
+    fn callback_urls_must_be_https_and_allowlisted() {
+        env::set_var("ALLOWED_CALLBACK_HOSTS", "hooks.example.com, example.org:8443");
+
+        assert_eq!(
+            validate_callback_url("https://hooks.example.com/options?id=1"),
+            Ok("https://hooks.example.com/options?id=1".to_string())
+        );
+        assert!(validate_callback_url("https://example.org:8443/hook").is_ok());
+        for rejected in [
+            "http://hooks.example.com/options",
+            "https://169.254.169.254/latest/meta-data/",
+            "https://example.org/hook",
+            "https://hooks.example.com.evil.test/",
+            "not a url",
+        ] {
+            assert!(validate_callback_url(rejected).is_err(), "{} should be rejected", rejected);
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_a_callback_url_outside_the_allowlist() {
+        let body = query(json!({ "ticker_symbol": "HCBX", "callback_url": "http://127.0.0.1:9/hook" })).await;
+
+        assert_eq!(body["error"]["field"], "callback_url");
+        assert!(mock().requests(|r| is_listing_request(r, "HCBX")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn posts_the_redacted_response_to_the_callback() {
+        let server = mock();
+        server.route(|request| (request.path == "/callback/posted").then(|| (200, "{}".to_string())));
+        let resp = Response {
+            req_id: "callback-request".to_string(),
+            response: json!({ "note": "made with secret-key" }).to_string(),
+        };
+
+        post_callback(&http_client(), &format!("{}/callback/posted", server.base_url), &resp, "secret-key").await;
+
+        let posted = server.requests(|r| r.path == "/callback/posted");
+        assert_eq!(posted.len(), 1);
+        assert_eq!(posted[0].method, "POST");
+        assert_eq!(posted[0].headers["idempotency-key"], "callback-request");
+        assert_eq!(posted[0].headers["content-type"], "application/json");
+        let delivered: Value = serde_json::from_str(&posted[0].body).unwrap();
+        assert_eq!(delivered["req_id"], "callback-request");
+        assert_eq!(delivered["response"], json!({ "note": "made with REDACTED" }).to_string());
+    }
+
+    fn format_options(fetched_spot: Option<f64>) -> FormatOptions {
+        FormatOptions {
+            precision: 2,
+            locale: None,
+            use_ask_for_cost: false,
+            include_greeks: false,
+            baseline_iv: None,
+            fetched_spot,
+            fetched_spot_updated_ns: None,
+            max_spot_age_secs: None,
+            now_ns: 0,
+            today: Local::now().date_naive(),
+        }
+    }
+
+    #[test]
+    fn serializes_notional_greeks_and_spot_divergence() {
+        let mut contract = snapshot("TYPD", "call", 150.0, &date_in(20));
+        contract["greeks"].as_object_mut().unwrap().remove("vega");
+
+        let formatted = format_contract(&contract, &format_options(None)).to_value(&format_options(None));
+        assert_eq!(
+            formatted["greeks_notional"],
+            json!({ "delta": 7500.0, "gamma": 450.0, "theta": -5.0, "vega": null })
+        );
+        assert!(formatted.get("spot_divergence").is_none());
+
+        let diverging = format_contract(&contract, &format_options(Some(160.0))).to_value(&format_options(None));
+        assert_eq!(diverging["spot_divergence"], json!({ "fetched": 160.0, "snapshot": 150.0 }));
+
+        let agreeing = format_contract(&contract, &format_options(Some(150.1))).to_value(&format_options(None));
+        assert!(agreeing.get("spot_divergence").is_none());
+        assert!(format_listing_entry(&listing_entry(&contract)).to_value(&format_options(None)).get("greeks_notional").is_none());
+    }
+
+    #[test]
+    fn notional_greeks_use_the_fetched_spot() {
+        let contract = snapshot("TNOT", "call", 150.0, &date_in(20));
+
+        let formatted = format_contract(&contract, &format_options(Some(160.0)));
+
+        assert_eq!(formatted.underlying_price, Some(160.0));
+        assert_eq!(formatted.spot_divergence, Some(SpotDivergence { fetched: 160.0, snapshot: 150.0 }));
+        let notional = formatted.greeks_notional.unwrap();
+        assert_eq!(notional.delta, Some(0.5 * 100.0 * 160.0));
+        assert_eq!(notional.gamma, Some(0.02 * 100.0 * 160.0 * 160.0 / 100.0));
+        assert_eq!(notional.theta, Some(-5.0));
+    }
+
+    #[test]
+    fn computes_the_spread_of_a_two_sided_quote() {
+        let (spread_abs, spread_rel) = compute_spread(&json!({ "bid": 1.2, "ask": 1.3, "midpoint": 1.25 }));
+        assert!((spread_abs.unwrap() - 0.1).abs() < 1e-9);
+        assert!((spread_rel.unwrap() - 0.08).abs() < 1e-9);
+
+        // Without a midpoint it is derived from the bid and ask.
+        let (_, spread_rel) = compute_spread(&json!({ "bid": 1.0, "ask": 3.0 }));
+        assert_eq!(spread_rel, Some(1.0));
+
+        // A zero midpoint leaves the relative spread undefined.
+        let (spread_abs, spread_rel) = compute_spread(&json!({ "bid": 1.0, "ask": 2.0, "midpoint": 0.0 }));
+        assert_eq!(spread_abs, Some(1.0));
+        assert_eq!(spread_rel, None);
+
+        for one_sided in [json!({ "bid": 0.0, "ask": 0.5 }), json!({ "bid": 0.5, "ask": 0.0 }), json!({ "ask": 0.5 })] {
+            assert_eq!(compute_spread(&one_sided), (None, None));
+        }
+    }
+
+    #[tokio::test]
+    async fn counts_listed_contracts_without_a_ticker() {
+        let contract = snapshot("HNTK", "call", 45.0, &date_in(4));
+        let mut untickered = listing_entry(&snapshot("HNTK", "call", 50.0, &date_in(4)));
+        untickered.as_object_mut().unwrap().remove("ticker");
+        let results = vec![listing_entry(&contract), untickered];
+        // Registered ahead of serve_chain's listing route, so it answers first.
+        mock().route(move |request| {
+            is_listing_request(request, "HNTK")
+                .then(|| (200, json!({ "status": "OK", "results": results }).to_string()))
+        });
+        serve_chain("HNTK", &[contract]);
+
+        let body = query(json!({ "ticker_symbol": "HNTK" })).await;
+
+        assert_eq!(contracts(&body).len(), 1);
+        assert_eq!(body["metadata"]["dropped_without_ticker"], 1);
+        assert!(body["warnings"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("1 listed contracts had no ticker and were skipped")));
+    }
+
+    #[test]
+    fn ranks_the_current_iv_against_its_history() {
+        let history = [0.2, 0.4, 0.3, 0.25];
+
+        assert!((iv_rank(0.35, &history).unwrap() - 75.0).abs() < 1e-9);
+        assert_eq!(iv_percentile(0.35, &history), Some(75.0));
+        assert_eq!(iv_rank(0.5, &history), Some(100.0));
+        assert_eq!(iv_rank(0.1, &history), Some(0.0));
+        // Ties aren't counted as below.
+        assert_eq!(iv_percentile(0.2, &history), Some(0.0));
+
+        assert_eq!(iv_rank(0.3, &[0.3, 0.3]), None);
+        assert_eq!(iv_rank(0.3, &[]), None);
+        assert_eq!(iv_percentile(0.3, &[]), None);
+    }
+
+    #[tokio::test]
+    async fn reports_iv_rank_from_a_supplied_history() {
+        serve_chain("HIVR", &[snapshot("HIVR", "call", 60.0, &date_in(7))]);
+
+        let body = query(json!({ "ticker_symbol": "HIVR", "iv_history": [0.2, 0.4, 0.25, 0.35] })).await;
+
+        assert_eq!(body["metadata"]["current_iv"], 0.3);
+        assert!((body["metadata"]["iv_rank"].as_f64().unwrap() - 50.0).abs() < 1e-9);
+        assert_eq!(body["metadata"]["iv_percentile"], 50.0);
+    }
+
+    #[test]
+    fn computes_dollar_delta_from_known_values() {
+        let contract = json!({
+            "details": { "shares_per_contract": 100 },
+            "greeks": { "delta": 0.5, "gamma": 0.25, "theta": -0.25, "vega": 0.125 },
+        });
+
+        let notional = notional_greeks(&contract, Some(200.0));
+
+        assert_eq!(notional.delta, Some(10_000.0));
+        assert_eq!(notional.gamma, Some(10_000.0));
+        assert_eq!(notional.theta, Some(-25.0));
+        assert_eq!(notional.vega, Some(12.5));
+
+        // No spot: the spot-scaled greeks are null, the rest still computed.
+        let notional = notional_greeks(&contract, None);
+        assert_eq!((notional.delta, notional.gamma), (None, None));
+        assert_eq!(notional.theta, Some(-25.0));
+        // No multiplier: nothing can be scaled.
+        assert_eq!(notional_greeks(&json!({ "greeks": contract["greeks"] }), Some(200.0)), NotionalGreeks::default());
+    }
+
+    #[tokio::test]
+    async fn falls_back_to_listing_data_when_every_snapshot_fails() {
+        let expiration = date_in(9);
+        let chain: Vec<Value> =
+            [80.0, 85.0, 90.0].iter().map(|&strike| snapshot("HFBK", "put", strike, &expiration)).collect();
+        let prefix = snapshot_path_prefix("HFBK");
+        mock().route(move |request| {
+            request
+                .path
+                .starts_with(&prefix)
+                .then(|| (403, json!({ "status": "NOT_AUTHORIZED", "message": "Not entitled" }).to_string()))
+        });
+        serve_chain("HFBK", &chain);
+
+        let body = query(json!({ "ticker_symbol": "HFBK", "contract_type": "put", "listing_fallback": true })).await;
+
+        let returned = contracts(&body);
+        assert_eq!(returned.len(), 3);
+        for (contract, snapshot) in returned.iter().zip(&chain) {
+            assert_eq!(contract["data_source"], "listing_only");
+            assert_eq!(contract["ticker"], snapshot["details"]["ticker"]);
+            assert_eq!(contract["strike_price"], snapshot["details"]["strike_price"]);
+            assert_eq!(contract["expiration_date"], expiration);
+            assert_eq!(contract["contract_type"], "put");
+            assert!(contract.get("bid").is_none());
+        }
+        assert_eq!(body["errors"].as_array().unwrap().len(), 3);
+    }
+
+    #[tokio::test]
+    async fn ramps_snapshot_batches_up_to_max_concurrency() {
+        let expiration = date_in(13);
+        let chain: Vec<Value> = (0..7)
+            .map(|i| snapshot("HRMP", "call", 100.0 + i as f64, &expiration))
+            .collect();
+        // Each request notes how many were in flight when it arrived, and is
+        // held long enough for the rest of its batch to arrive.
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let arrivals = Arc::new(Mutex::new(Vec::new()));
+        let (prefix, counter, seen) = (snapshot_path_prefix("HRMP"), in_flight.clone(), arrivals.clone());
+        mock().route(move |request| {
+            if request.path.starts_with(&prefix) {
+                seen.lock().unwrap().push(counter.fetch_add(1, Ordering::SeqCst) + 1);
+                std::thread::sleep(StdDuration::from_millis(50));
+                counter.fetch_sub(1, Ordering::SeqCst);
+            }
+            None
+        });
+        serve_chain("HRMP", &chain);
+
+        let body =
+            query(json!({ "ticker_symbol": "HRMP", "ramp_concurrency": true, "max_concurrency": "4" })).await;
+
+        assert_eq!(contracts(&body).len(), 7);
+        // Batches of 1, 2 and 4.
+        assert_eq!(*arrivals.lock().unwrap(), vec![1, 1, 2, 1, 2, 3, 4]);
+    }
+
+    #[tokio::test]
+    async fn echoes_the_tag_on_every_contract() {
+        let expiration = date_in(15);
+        let chain: Vec<Value> =
+            [20.0, 22.5, 25.0].iter().map(|&strike| snapshot("HTAG", "call", strike, &expiration)).collect();
+        serve_chain("HTAG", &chain);
+
+        let body = query(json!({ "ticker_symbol": "HTAG", "tag": "watchlist-7" })).await;
+
+        assert_eq!(contracts(&body).len(), 3);
+        assert!(contracts(&body).iter().all(|contract| contract["tag"] == "watchlist-7"));
+        assert_eq!(body["metadata"]["tag"], "watchlist-7");
+    }
+
+    #[tokio::test]
+    async fn follows_next_url_across_an_empty_page() {
+        let expiration = date_in(16);
+        let first = snapshot("HPAG", "call", 10.0, &expiration);
+        let last = snapshot("HPAG", "call", 12.0, &expiration);
+        let server = mock();
+        let page_url = |cursor: &str| format!("{}/v3/reference/options/contracts?cursor={}", server.base_url, cursor);
+        let (second_page, third_page) = (page_url("HPAG2"), page_url("HPAG3"));
+        let (first_entry, last_entry) = (listing_entry(&first), listing_entry(&last));
+        server.route(move |request| {
+            let body = if is_listing_request(request, "HPAG") {
+                json!({ "status": "OK", "results": [first_entry], "next_url": second_page })
+            } else {
+                match request.query.get("cursor").map(String::as_str) {
+                    Some("HPAG2") => json!({ "status": "OK", "results": [], "next_url": third_page }),
+                    Some("HPAG3") => json!({ "status": "OK", "results": [last_entry] }),
+                    _ => return None,
+                }
+            };
+            Some((200, body.to_string()))
+        });
+        serve_chain("HPAG", &[first, last]);
+
+        let body = query(json!({ "ticker_symbol": "HPAG" })).await;
+
+        let strikes: Vec<f64> = contracts(&body).iter().map(|c| c["strike_price"].as_f64().unwrap()).collect();
+        assert_eq!(strikes, vec![10.0, 12.0]);
+        assert_eq!(mock().requests(|r| r.query.get("cursor").is_some_and(|c| c.starts_with("HPAG"))).len(), 2);
+        // Every page was read, so the total is known.
+        assert_eq!(body["metadata"]["coverage"]["total_in_window"], 2);
+    }
+
+    #[test]
+    fn prices_contract_cost_at_the_mid_or_the_ask() {
+        let contract = json!({
+            "details": { "shares_per_contract": 100 },
+            "last_quote": { "bid": 2.0, "ask": 2.5, "midpoint": 2.25 },
+        });
+        assert_eq!(compute_contract_cost(&contract, false), (Some(225.0), "mid", false));
+        assert_eq!(compute_contract_cost(&contract, true), (Some(250.0), "ask", false));
+
+        // Without an ask the midpoint is used, and flagged.
+        let no_ask = json!({ "last_quote": { "bid": 2.0, "ask": 0.0, "midpoint": 1.0 } });
+        assert_eq!(compute_contract_cost(&no_ask, true), (Some(100.0), "mid", true));
+        assert_eq!(compute_contract_cost(&no_ask, false), (Some(100.0), "mid", false));
+    }
+
+    #[tokio::test]
+    async fn keeps_the_top_n_by_open_interest() {
+        let expiration = date_in(17);
+        let chain: Vec<Value> = [(30.0, 300), (31.0, 900), (32.0, 100), (33.0, 600)]
+            .iter()
+            .map(|&(strike, open_interest)| {
+                let mut contract = snapshot("HTOP", "call", strike, &expiration);
+                contract["open_interest"] = json!(open_interest);
+                contract
+            })
+            .collect();
+        serve_chain("HTOP", &chain);
+
+        let body = query(json!({
+            "ticker_symbol": "HTOP",
+            "sort_by": "open_interest",
+            "sort_order": "desc",
+            "top_n": "2",
+        }))
+        .await;
+
+        let open_interest: Vec<u64> = contracts(&body).iter().map(|c| c["open_interest"].as_u64().unwrap()).collect();
+        assert_eq!(open_interest, vec![900, 600]);
+    }
+
+    #[tokio::test]
+    async fn include_zero_oi_keeps_or_drops_unopened_contracts() {
+        let expiration = date_in(18);
+        let mut zero = snapshot("HZOI", "call", 40.0, &expiration);
+        zero["open_interest"] = json!(0);
+        let mut missing = snapshot("HZOI", "call", 41.0, &expiration);
+        missing.as_object_mut().unwrap().remove("open_interest");
+        serve_chain("HZOI", &[zero, missing, snapshot("HZOI", "call", 42.0, &expiration)]);
+
+        let cases = [
+            (json!(null), vec![40.0, 41.0, 42.0]),
+            (json!(true), vec![40.0, 41.0, 42.0]),
+            (json!(false), vec![42.0]),
+        ];
+        for (include_zero_oi, expected) in cases {
+            let body = query(json!({ "ticker_symbol": "HZOI", "include_zero_oi": include_zero_oi })).await;
+
+            let strikes: Vec<f64> = contracts(&body).iter().map(|c| c["strike_price"].as_f64().unwrap()).collect();
+            assert_eq!(strikes, expected, "include_zero_oi: {}", include_zero_oi);
+        }
+    }
+
+    #[tokio::test]
+    async fn drops_contracts_over_the_detail_fetch_cap() {
+        let expiration = date_in(19);
+        let chain: Vec<Value> =
+            [5.0, 6.0, 7.0, 8.0].iter().map(|&strike| snapshot("HCAP", "call", strike, &expiration)).collect();
+        serve_chain("HCAP", &chain);
+        let payload: Payload = serde_json::from_value(json!({ "ticker_symbol": "HCAP", "api_key": "test-key" })).unwrap();
+
+        // As if MAX_DETAIL_FETCHES were 2.
+        let resp = handle_query(payload, "req".to_string(), None, &FetchBudget::new(20, 2)).await.unwrap();
+        let body: Value = serde_json::from_str(&resp.response).unwrap();
+
+        assert_eq!(contracts(&body).len(), 2);
+        assert_eq!(body["metadata"]["truncated"], true);
+        assert_eq!(body["metadata"]["truncated_count"], 2);
+        assert_eq!(mock().requests(|r| r.path.starts_with(&snapshot_path_prefix("HCAP"))).len(), 2);
+        assert!(body["warnings"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("2 contracts were dropped because the invocation hit the cap of 2 snapshot fetches")));
+    }
+
+    #[tokio::test]
+    async fn pairs_calls_and_puts_at_each_strike() {
+        let expiration = date_in(20);
+        let mut put = snapshot("HPAR", "put", 100.0, &expiration);
+        put["last_quote"]["midpoint"] = json!(1.5);
+        put["greeks"]["delta"] = json!(-0.375);
+        let chain = [
+            snapshot("HPAR", "call", 100.0, &expiration),
+            put,
+            // No put at this strike, so it isn't paired.
+            snapshot("HPAR", "call", 105.0, &expiration),
+        ];
+        serve_chain("HPAR", &chain);
+
+        let body = query(json!({ "ticker_symbol": "HPAR", "pair_by_strike": true, "limit": "10" })).await;
+
+        let pairs = body["strike_pairs"].as_array().unwrap();
+        assert_eq!(pairs.len(), 1);
+        let pair = &pairs[0];
+        assert_eq!(pair["expiration_date"], expiration);
+        assert_eq!(pair["strike_price"], 100.0);
+        assert_eq!(pair["call"]["ticker"], ticker_of(&chain[0]));
+        assert_eq!(pair["put"]["ticker"], ticker_of(&chain[1]));
+        assert_eq!(pair["combined_premium"], 2.75);
+        assert_eq!(pair["net_delta"], 0.125);
+    }
+
+    #[test]
+    fn the_same_seed_samples_the_same_strikes() {
+        let grid: Vec<f64> = (0..20).map(|i| 50.0 + i as f64).collect();
+        let (entries, tickers) = listing_entries(&grid);
+        let mut reversed = tickers.clone();
+        reversed.reverse();
+
+        let sample = |tickers: &[String], seed: u64| {
+            sample_listing_strikes(&entries, tickers, 5, &mut StdRng::seed_from_u64(seed))
+        };
+
+        let first = sample(&tickers, 42);
+        assert_eq!(first.len(), 5);
+        assert!(first.windows(2).all(|pair| pair[0] < pair[1]));
+        // Listing order doesn't matter, only the seed.
+        assert_eq!(sample(&reversed, 42), first);
+        assert_eq!(sample(&tickers, 42), first);
+        assert_ne!(sample(&tickers, 7), first);
+    }
+
+    #[tokio::test]
+    async fn coverage_counts_the_chain_beyond_the_limit() {
+        let expiration = date_in(21);
+        let chain: Vec<Value> = (0..30)
+            .map(|i| snapshot("HCOV", "call", 60.0 + i as f64, &expiration))
+            .collect();
+        // Pages of `limit` contracts, chained through `next_url` cursors and
+        // with no total, the way Polygon serves them.
+        let entries: Vec<Value> = chain.iter().map(listing_entry).collect();
+        let server = mock();
+        let base_url = server.base_url.clone();
+        server.route(move |request| {
+            let (offset, page_size): (usize, usize) = if is_listing_request(request, "HCOV") {
+                (0, request.query["limit"].parse().ok()?)
+            } else {
+                let (offset, page_size) = request.query.get("cursor")?.strip_prefix("HCOV:")?.split_once(':')?;
+                (offset.parse().ok()?, page_size.parse().ok()?)
+            };
+            let end = (offset + page_size).min(entries.len());
+            let mut page = json!({ "status": "OK", "results": entries[offset..end] });
+            if end < entries.len() {
+                page["next_url"] = json!(format!(
+                    "{}/v3/reference/options/contracts?cursor=HCOV:{}:{}",
+                    base_url, end, page_size
+                ));
+            }
+            Some((200, page.to_string()))
+        });
+        serve_chain("HCOV", &chain);
+
+        // The total counts past the limit, after the strike filter.
+        let body = query(json!({ "ticker_symbol": "HCOV", "limit": 2, "strike_increment": "2" })).await;
+        assert_eq!(contracts(&body).len(), 2);
+        assert_eq!(body["metadata"]["coverage"]["returned"], 2);
+        assert_eq!(body["metadata"]["coverage"]["total_in_window"], 15);
+        assert_eq!(mock().requests(|r| r.query.get("cursor").is_some_and(|c| c.ends_with(":2"))).len(), 14);
+
+        // A chain longer than the page cap leaves the total unknown.
+        let body = query(json!({ "ticker_symbol": "HCOV", "limit": 1 })).await;
+        assert_eq!(contracts(&body).len(), 1);
+        assert!(body["metadata"]["coverage"]["total_in_window"].is_null());
+    }
+
+    #[test]
+    fn computes_leverage_from_known_inputs() {
+        assert_eq!(compute_leverage(Some(0.5), Some(200.0), Some(4.0)), Some(25.0));
+        assert_eq!(compute_leverage(Some(-0.25), Some(200.0), Some(2.0)), Some(-25.0));
+
+        assert_eq!(compute_leverage(Some(0.5), Some(200.0), Some(0.0)), None);
+        assert_eq!(compute_leverage(None, Some(200.0), Some(4.0)), None);
+        assert_eq!(compute_leverage(Some(0.5), None, Some(4.0)), None);
+        assert_eq!(compute_leverage(Some(0.5), Some(200.0), None), None);
+    }
+
+    #[tokio::test]
+    async fn prices_the_atm_straddle_at_the_nearest_expiration() {
+        let (near, far) = (date_in(5), date_in(12));
+        let mut chain = Vec::new();
+        for expiration in [&near, &far] {
+            for strike in [145.0, 150.0, 155.0] {
+                chain.push(snapshot("HSTR", "call", strike, expiration));
+                let mut put = snapshot("HSTR", "put", strike, expiration);
+                put["last_quote"]["midpoint"] = json!(2.0);
+                chain.push(put);
+            }
+        }
+        serve_chain("HSTR", &chain);
+
+        // The snapshots put the underlying at 150.
+        let body = query(json!({ "ticker_symbol": "HSTR", "action": "atm_straddle", "limit": "20" })).await;
+
+        let straddle = &body["atm_straddle"];
+        assert_eq!(straddle["expiration_date"], near);
+        assert_eq!(straddle["strike_price"], 150.0);
+        assert_eq!(straddle["underlying_price"], 150.0);
+        assert_eq!(straddle["straddle_price"], 3.25);
+        assert!((straddle["implied_move_pct"].as_f64().unwrap() - 3.25 / 150.0 * 100.0).abs() < 1e-9);
+        assert_eq!(straddle["call"]["contract_type"], "call");
+        assert_eq!(straddle["put"]["contract_type"], "put");
+    }
+
+    #[test]
+    fn skips_holidays_when_counting_trading_days() {
+        let date = |s: &str| NaiveDate::parse_from_str(s, "%Y-%m-%d").unwrap();
+
+        // Thanksgiving 2025 (Thursday the 27th) and the weekend.
+        assert_eq!(trading_days_between(date("2025-11-26"), date("2025-12-01")), 2);
+        assert_eq!(trading_days_between(date("2025-12-01"), date("2025-11-26")), -2);
+        // Good Friday 2025.
+        assert_eq!(trading_days_between(date("2025-04-17"), date("2025-04-21")), 1);
+        assert_eq!(trading_days_between(date("2025-04-18"), date("2025-04-18")), 0);
+
+        // April 2025's third Friday is Good Friday, so the monthly is Thursday.
+        assert!(is_monthly_expiration(date("2025-04-17")));
+        assert!(!is_monthly_expiration(date("2025-04-18")));
+        assert!(is_monthly_expiration(date("2025-05-16")));
+        assert!(!is_monthly_expiration(date("2025-05-15")));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_zero_limit() {
+        for limit in [json!(0), json!("0"), json!(-3), json!("abc")] {
+            let body = query(json!({ "ticker_symbol": "HLIM", "limit": limit })).await;
+
+            assert_eq!(body["error"]["status_code"], 400, "limit: {}", limit);
+            assert_eq!(body["error"]["field"], "limit");
+        }
+        assert!(mock().requests(|r| is_listing_request(r, "HLIM")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn data_delayed_reflects_the_configured_tier() {
+        serve_chain("HTIR", &[snapshot("HTIR", "call", 25.0, &date_in(6))]);
+        // POLYGON_DATA_TIER is never set in tests, so the default is real-time.
+        assert_eq!(DataTier::from_env(), DataTier::Realtime);
+
+        for (data_tier, delayed) in [(json!("delayed"), true), (json!("Real-Time"), false), (json!(null), false)] {
+            let body = query(json!({ "ticker_symbol": "HTIR", "data_tier": data_tier })).await;
+
+            assert_eq!(body["metadata"]["data_delayed"], delayed, "data_tier: {}", data_tier);
+        }
+    }
+
+    #[tokio::test]
+    async fn compact_output_round_trips_to_the_full_format() {
+        let expiration = date_in(22);
+        let mut sparse = snapshot("HCMP", "call", 91.0, &expiration);
+        sparse.as_object_mut().unwrap().remove("greeks");
+        serve_chain("HCMP", &[snapshot("HCMP", "call", 90.0, &expiration), sparse]);
+
+        let full = query(json!({ "ticker_symbol": "HCMP" })).await;
+        let compact = query(json!({ "ticker_symbol": "HCMP", "output_format": "compact" })).await;
+
+        let header: Vec<&str> =
+            compact["option_contracts"]["header"].as_array().unwrap().iter().map(|f| f.as_str().unwrap()).collect();
+        assert!(header.windows(2).all(|pair| pair[0] < pair[1]));
+        let rebuilt: Vec<Value> = compact["option_contracts"]["data"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|row| {
+                let fields = header
+                    .iter()
+                    .zip(row.as_array().unwrap())
+                    .filter(|(_, value)| !value.is_null())
+                    .map(|(field, value)| (field.to_string(), value.clone()));
+                Value::Object(fields.collect())
+            })
+            .collect();
+        assert_eq!(&rebuilt, contracts(&full));
+    }
+
+    #[test]
+    fn clamps_percentages_into_range() {
+        let mut warnings = Vec::new();
+
+        assert_eq!(validate_pct("moneyness_pct", Some("-5"), &mut warnings), Some(0.0));
+        assert_eq!(validate_pct("max_otm_pct", Some("150"), &mut warnings), Some(100.0));
+        assert_eq!(warnings, ["Clamped moneyness_pct from -5 to 0", "Clamped max_otm_pct from 150 to 100"]);
+
+        warnings.clear();
+        assert_eq!(validate_pct("max_spread_pct", Some(" 12.5 "), &mut warnings), Some(12.5));
+        assert_eq!(validate_pct("max_spread_pct", None, &mut warnings), None);
+        assert!(warnings.is_empty());
+
+        assert_eq!(validate_pct("moneyness_pct", Some("ten"), &mut warnings), None);
+        assert_eq!(validate_pct("moneyness_pct", Some("inf"), &mut warnings), None);
+        assert_eq!(warnings.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn sorts_by_expiration_then_distance_from_atm() {
+        let (near, far) = (date_in(8), date_in(15));
+        // Listed out of order; the snapshots put the underlying at 150.
+        let chain: Vec<Value> = [(&far, 140.0), (&near, 165.0), (&far, 152.5), (&near, 145.0), (&near, 150.0)]
+            .iter()
+            .map(|&(expiration, strike)| snapshot("HEAT", "call", strike, expiration))
+            .collect();
+        serve_chain("HEAT", &chain);
+
+        let body = query(json!({ "ticker_symbol": "HEAT", "sort_by": "expiration_then_atm" })).await;
+
+        let order: Vec<(String, f64)> = contracts(&body)
+            .iter()
+            .map(|c| (c["expiration_date"].as_str().unwrap().to_string(), c["strike_price"].as_f64().unwrap()))
+            .collect();
+        assert_eq!(
+            order,
+            vec![
+                (near.clone(), 150.0),
+                (near.clone(), 145.0),
+                (near.clone(), 165.0),
+                (far.clone(), 152.5),
+                (far.clone(), 140.0),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn rejects_oversized_extra_params_and_requests() {
+        let extra_params: serde_json::Map<String, Value> =
+            (0..=DEFAULT_MAX_EXTRA_PARAMS).map(|i| (format!("param_{}", i), json!("1"))).collect();
+        let body = query(json!({ "ticker_symbol": "HXTR", "extra_params": extra_params })).await;
+        assert_eq!(body["error"]["status_code"], 400);
+        assert_eq!(
+            body["error"]["message"],
+            format!(
+                "extra_params has {} entries, over the limit of {}",
+                DEFAULT_MAX_EXTRA_PARAMS + 1,
+                DEFAULT_MAX_EXTRA_PARAMS
+            )
+        );
+
+        let padding = "x".repeat(DEFAULT_MAX_REQUEST_BYTES);
+        let body = query(json!({ "ticker_symbol": "HXTR", "tag": padding })).await;
+        assert_eq!(body["error"]["status_code"], 400);
+        assert!(body["error"]["message"].as_str().unwrap().contains("byte limit"));
+
+        assert!(mock().requests(|r| is_listing_request(r, "HXTR")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn strike_prices_come_out_without_float_noise() {
+        assert_eq!(round_strike(150.00000000000001), 150.0);
+        assert_eq!(round_strike(152.49999999999997), 152.5);
+        assert_eq!(round_strike(0.1 + 0.2), 0.3);
+        assert_eq!(round_strike(7.125), 7.125);
+
+        let expiration = date_in(23);
+        let mut noisy = snapshot("HRND", "call", 152.5, &expiration);
+        noisy["details"]["strike_price"] = json!(152.49999999999997);
+        serve_chain("HRND", &[noisy]);
+
+        let body = query(json!({ "ticker_symbol": "HRND" })).await;
+
+        assert_eq!(contracts(&body)[0]["strike_price"].to_string(), "152.5");
+    }
+
+    #[tokio::test]
+    async fn rejects_a_window_with_no_overlap() {
+        let body = query(json!({ "ticker_symbol": "HCFW", "days_forward": 10, "expiration_start": date_in(30) })).await;
+
+        assert_eq!(body["error"]["status_code"], 400);
+        let message = body["error"]["message"].as_str().unwrap();
+        assert!(message.starts_with("Conflicting expiration parameters"), "{}", message);
+        assert!(message.contains(&format!("starts {}", date_in(30))));
+        assert!(message.contains(&format!("ends {}", date_in(10))));
+        assert!(mock().requests(|r| is_listing_request(r, "HCFW")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn reports_the_open_interest_change() {
+        let expiration = date_in(24);
+        let mut grown = snapshot("HOIC", "call", 35.0, &expiration);
+        grown["previous_open_interest"] = json!(800);
+        let mut shrunk = snapshot("HOIC", "call", 36.0, &expiration);
+        shrunk["day"]["previous_open_interest"] = json!(1250);
+        let mut new_strike = snapshot("HOIC", "call", 37.0, &expiration);
+        new_strike["previous_open_interest"] = json!(0);
+        serve_chain("HOIC", &[grown, shrunk, new_strike, snapshot("HOIC", "call", 38.0, &expiration)]);
+
+        let body = query(json!({ "ticker_symbol": "HOIC" })).await;
+
+        let changes: Vec<(Value, Value)> =
+            contracts(&body).iter().map(|c| (c["oi_change"].clone(), c["oi_change_pct"].clone())).collect();
+        assert_eq!(
+            changes,
+            vec![
+                (json!(200), json!(25.0)),
+                (json!(-250), json!(-20.0)),
+                (json!(1000), Value::Null),
+                (Value::Null, Value::Null),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn selects_a_nested_subfield_only() {
+        let contract = json!({
+            "ticker": "O:X",
+            "bid": 1.0,
+            "greeks_notional": { "delta": 5000.0, "gamma": 12.0 },
+            "history": [[ "2024-01-02", 1.0 ]],
+        });
+        let select = json!({ "greeks_notional": { "delta": true }, "ask": true, "bid": false });
+        assert_eq!(apply_selection(&contract, &select), json!({ "greeks_notional": { "delta": 5000.0 } }));
+        assert_eq!(
+            apply_selection(&json!([contract.clone(), contract]), &json!({ "ticker": true })),
+            json!([{ "ticker": "O:X" }, { "ticker": "O:X" }])
+        );
+
+        serve_chain("HSEL", &[snapshot("HSEL", "call", 15.0, &date_in(25))]);
+        let body = query(json!({ "ticker_symbol": "HSEL", "select": { "greeks_notional": { "delta": true } } })).await;
+
+        assert_eq!(contracts(&body), &vec![json!({ "greeks_notional": { "delta": 0.5 * 100.0 * 150.0 } })]);
+    }
+
+    #[tokio::test]
+    async fn refuses_a_next_url_on_a_foreign_host() {
+        let base = "https://api.polygon.io/v3/reference/options/contracts";
+        assert!(ensure_same_host("https://api.polygon.io/v3/reference/options/contracts?cursor=a", base).is_ok());
+        for foreign in [
+            "https://evil.example.com/v3/reference/options/contracts?cursor=a",
+            "http://api.polygon.io/v3/reference/options/contracts?cursor=a",
+            "https://api.polygon.io:8443/v3/reference/options/contracts?cursor=a",
+        ] {
+            assert!(ensure_same_host(foreign, base).is_err(), "{}", foreign);
+        }
+
+        let contract = snapshot("HSSR", "call", 45.0, &date_in(4));
+        let entry = listing_entry(&contract);
+        mock().route(move |request| {
+            is_listing_request(request, "HSSR").then(|| {
+                let next_url = "https://evil.example.com/v3/reference/options/contracts?cursor=HSSR2";
+                (200, json!({ "status": "OK", "results": [entry], "next_url": next_url }).to_string())
+            })
+        });
+        serve_chain("HSSR", &[contract]);
+
+        let payload = json!({ "ticker_symbol": "HSSR", "api_key": "test-key", "limit": "5" });
+        let Err(error) = function_handler(LambdaEvent::new(payload, Context::default())).await else {
+            panic!("the foreign next_url was followed");
+        };
+
+        assert!(error.to_string().contains("Refusing to follow next_url on unexpected host \"evil.example.com\""));
+    }
+
+    #[tokio::test]
+    async fn demo_mode_answers_an_empty_invocation_with_usage() {
+        env::set_var("DEMO_MODE", "true");
+
+        let body = invoke(json!({})).await;
+
+        assert_eq!(body, usage());
+        assert!(body["usage"]["parameters"]["ticker_symbol"].is_string());
+        // Any parameter at all is a real query.
+        let body = invoke(json!({ "ticker_symbol": "" })).await;
+        assert_eq!(body["error"]["field"], "ticker_symbol");
+    }
+
+    #[tokio::test]
+    async fn reports_bid_and_ask_sizes_when_quoted() {
+        let expiration = date_in(18);
+        let sized = snapshot("HSIZ", "call", 150.0, &expiration);
+        let mut unsized_quote = snapshot("HSIZ", "call", 155.0, &expiration);
+        let quote = unsized_quote["last_quote"].as_object_mut().unwrap();
+        quote.remove("bid_size");
+        quote.remove("ask_size");
+        serve_chain("HSIZ", &[sized, unsized_quote]);
+
+        let body = query(json!({ "ticker_symbol": "HSIZ" })).await;
+        let at_strike = |strike: f64| contracts(&body).iter().find(|c| c["strike_price"] == strike).unwrap().clone();
+
+        let sized = at_strike(150.0);
+        assert_eq!(sized["bid_size"], 10);
+        assert_eq!(sized["ask_size"], 12);
+        let unsized_quote = at_strike(155.0);
+        assert!(unsized_quote.get("bid_size").is_none() && unsized_quote.get("ask_size").is_none());
+    }
+
+    #[tokio::test]
+    async fn keeps_0dte_computations_finite() {
+        assert_eq!(years_to_expiration(-1), None);
+        assert_eq!(years_to_expiration(0), Some(MIN_YEARS_TO_EXPIRATION));
+        assert_eq!(years_to_expiration(126), Some(0.5));
+        assert_eq!(compute_expected_move(Some(150.0), Some(0.3), Some(-1)), None);
+        let expected_move = compute_expected_move(Some(150.0), Some(0.3), Some(0)).unwrap();
+        assert!((expected_move - 150.0 * 0.3 * MIN_YEARS_TO_EXPIRATION.sqrt()).abs() < 1e-9);
+
+        serve_chain("HZDT", &[snapshot("HZDT", "call", 150.0, &date_in(0))]);
+        let body = query(json!({ "ticker_symbol": "HZDT" })).await;
+
+        let contract = &contracts(&body)[0];
+        assert_eq!(contract["days_to_expiration"], 0);
+        assert!(contract["expected_move"].as_f64().is_some_and(|m| m.is_finite() && m > 0.0));
+    }
+
+    #[tokio::test]
+    async fn keys_the_ticker_map_by_occ_ticker() {
+        let expiration = date_in(12);
+        let chain = [snapshot("HMAP", "call", 150.0, &expiration), snapshot("HMAP", "call", 160.0, &expiration)];
+        serve_chain("HMAP", &chain);
+
+        let array = query(json!({ "ticker_symbol": "HMAP" })).await;
+        let map = query(json!({ "ticker_symbol": "HMAP", "output_format": "map_by_ticker" })).await;
+
+        let map = map["option_contracts"].as_object().unwrap();
+        let mut keys: Vec<&String> = map.keys().collect();
+        keys.sort();
+        let mut expected: Vec<String> = chain.iter().map(ticker_of).collect();
+        expected.sort();
+        assert_eq!(keys, expected.iter().collect::<Vec<_>>());
+        for summary in contracts(&array) {
+            assert_eq!(&map[summary["ticker"].as_str().unwrap()], summary);
+        }
+
+        // The keys survive a selection that drops the ticker itself.
+        let selected = query(json!({
+            "ticker_symbol": "HMAP",
+            "output_format": "map_by_ticker",
+            "select": { "strike_price": true },
+        }))
+        .await;
+        assert_eq!(selected["option_contracts"][ticker_of(&chain[1])], json!({ "strike_price": 160.0 }));
+    }
+
+    #[test]
+    fn approximates_probability_touch_from_delta() {
+        assert_eq!(compute_probability_touch(Some(0.25)), Some(0.5));
+        assert_eq!(compute_probability_touch(Some(-0.125)), Some(0.25));
+        assert_eq!(compute_probability_touch(Some(0.5)), Some(1.0));
+        assert_eq!(compute_probability_touch(Some(-0.8)), Some(1.0));
+        assert_eq!(compute_probability_touch(Some(f64::NAN)), None);
+        assert_eq!(compute_probability_touch(None), None);
+    }
+
+    #[tokio::test]
+    async fn warns_when_the_snapshot_contradicts_the_listed_contract_type() {
+        let mut contract = snapshot("HCTM", "call", 150.0, &date_in(9));
+        let entry = listing_entry(&contract);
+        contract["details"]["contract_type"] = json!("put");
+        let ticker = ticker_of(&contract);
+        mock().route(move |request| {
+            is_listing_request(request, "HCTM")
+                .then(|| (200, json!({ "status": "OK", "results": [entry] }).to_string()))
+        });
+        serve_chain("HCTM", &[contract]);
+
+        let body = query(json!({ "ticker_symbol": "HCTM" })).await;
+
+        assert_eq!(contracts(&body)[0]["contract_type"], "put");
+        assert!(body["warnings"]
+            .as_array()
+            .unwrap()
+            .contains(&json!(format!("{}: listed as call but snapshot reports put; using the snapshot", ticker))));
+    }
+
+    #[tokio::test]
+    async fn formats_numbers_for_the_de_de_locale() {
+        let german = NumberLocale::parse("de-DE").unwrap();
+        assert_eq!(german.format(1234.56, 2), "1.234,56");
+        assert_eq!(german.format(-1234567.5, 1), "-1.234.567,5");
+        assert_eq!(german.parse_number("1.234,56"), Some(1234.56));
+        assert_eq!(NumberLocale::parse("en-US").unwrap().format(1234.56, 2), "1,234.56");
+
+        serve_chain("HLOC", &[snapshot("HLOC", "call", 1500.0, &date_in(16))]);
+        let body = query(json!({ "ticker_symbol": "HLOC", "locale": "de_DE" })).await;
+
+        let contract = &contracts(&body)[0];
+        assert_eq!(contract["strike_price"], "1.500");
+        assert_eq!(contract["contract_cost"], "125,00");
+        assert_eq!(contract["implied_volatility"], "30,00%");
+    }
+
+    #[tokio::test]
+    async fn keeps_only_contracts_inside_the_gamma_band() {
+        let expiration = date_in(14);
+        let mut chain = Vec::new();
+        let gammas = [(140.0, json!(0.01)), (145.0, json!(0.02)), (150.0, json!(0.03)), (155.0, json!(0.05))];
+        for (strike, gamma) in gammas.into_iter().chain([(160.0, Value::Null)]) {
+            let mut contract = snapshot("HGAM", "call", strike, &expiration);
+            contract["greeks"]["gamma"] = gamma;
+            chain.push(contract);
+        }
+        serve_chain("HGAM", &chain);
+
+        let body = query(json!({ "ticker_symbol": "HGAM", "min_gamma": "0.02", "max_gamma": "0.04" })).await;
+
+        let strikes: Vec<f64> = contracts(&body).iter().map(|c| c["strike_price"].as_f64().unwrap()).collect();
+        assert_eq!(strikes, vec![145.0, 150.0]);
+    }
+
+    #[tokio::test]
+    async fn max_cost_drops_contracts_priced_over_the_budget() {
+        let expiration = date_in(11);
+        let mut chain = Vec::new();
+        for (strike, ask, shares_per_contract) in [
+            (140.0, json!(2.0), 100),
+            (145.0, json!(5.0), 100),
+            (150.0, json!(5.01), 100),
+            (155.0, json!(20.0), 10),
+            (160.0, json!(40.0), 100),
+            (165.0, Value::Null, 100),
+        ] {
+            let mut contract = snapshot("HBUD", "call", strike, &expiration);
+            contract["last_quote"]["ask"] = ask;
+            contract["details"]["shares_per_contract"] = json!(shares_per_contract);
+            chain.push(contract);
+        }
+        serve_chain("HBUD", &chain);
+
+        let body = query(json!({ "ticker_symbol": "HBUD", "max_cost": "500" })).await;
+
+        // A $5.00 ask is exactly $500; the mini contract costs $200; the
+        // contract without an ask can't be priced conservatively.
+        let strikes: Vec<f64> = contracts(&body).iter().map(|c| c["strike_price"].as_f64().unwrap()).collect();
+        assert_eq!(strikes, vec![140.0, 145.0, 155.0]);
+    }
+
+    #[tokio::test]
+    async fn echoes_the_correlation_id_from_the_header_or_the_body() {
+        serve_chain("HCOR", &[snapshot("HCOR", "call", 150.0, &date_in(8))]);
+
+        let proxied = |headers: Value| {
+            invoke(json!({
+                "queryStringParameters": { "ticker_symbol": "HCOR", "api_key": "test-key" },
+                "headers": headers,
+            }))
+        };
+        assert_eq!(proxied(json!({ "X-Correlation-Id": "trace-h" })).await["correlation_id"], "trace-h");
+        assert_eq!(proxied(json!({ "x-correlation-id": "trace-l" })).await["correlation_id"], "trace-l");
+        let from_body = query(json!({ "ticker_symbol": "HCOR", "correlation_id": "trace-b" })).await;
+        assert_eq!(from_body["correlation_id"], "trace-b");
+        let neither = query(json!({ "ticker_symbol": "HCOR" })).await;
+        assert!(neither.get("correlation_id").is_none());
+    }
+
+    #[tokio::test]
+    async fn records_an_error_shaped_snapshot_as_a_fetch_error() {
+        let expiration = date_in(13);
+        let good = snapshot("HESR", "call", 150.0, &expiration);
+        let object_error = snapshot("HESR", "call", 155.0, &expiration);
+        let string_error = snapshot("HESR", "call", 160.0, &expiration);
+        let path = |contract: &Value| format!("{}{}", snapshot_path_prefix("HESR"), ticker_of(contract));
+        let error_paths = [
+            (path(&object_error), json!({ "message": "Contract not found" })),
+            (path(&string_error), json!("Snapshot unavailable")),
+        ];
+        mock().route(move |request| {
+            let (_, error) = error_paths.iter().find(|(path, _)| request.path == *path)?;
+            Some((200, json!({ "status": "OK", "results": { "error": error } }).to_string()))
+        });
+        serve_chain("HESR", &[good.clone(), object_error.clone(), string_error.clone()]);
+
+        let body = query(json!({ "ticker_symbol": "HESR" })).await;
+
+        assert_eq!(contracts(&body).len(), 1);
+        assert_eq!(contracts(&body)[0]["ticker"], ticker_of(&good));
+        let mut errors = body["errors"].as_array().unwrap().clone();
+        errors.sort_by_key(|error| error["ticker"].as_str().unwrap().to_string());
+        assert_eq!(
+            errors,
+            vec![
+                json!({
+                    "ticker": ticker_of(&object_error),
+                    "status": null,
+                    "message": "snapshot returned an error: Contract not found",
+                }),
+                json!({
+                    "ticker": ticker_of(&string_error),
+                    "status": null,
+                    "message": "snapshot returned an error: Snapshot unavailable",
+                }),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn send_with_retry_sends_a_failing_post_once() {
+        let server = mock();
+        server.route(|request| request.path.starts_with("/hpst/").then(|| (503, "unavailable".to_string())));
+        let requests_sent = AtomicUsize::new(0);
+        let policy = RetryPolicy {
+            max_retries: 2,
+            base_delay: StdDuration::from_millis(1),
+            requests_sent: &requests_sent,
+        };
+        let client = Client::new();
+
+        let response = send_with_retry(client.post(format!("{}/hpst/post", server.base_url)), policy).await.unwrap();
+        assert_eq!(response.status(), 503);
+        assert_eq!(server.requests(|r| r.path == "/hpst/post").len(), 1);
+        assert_eq!(requests_sent.load(Ordering::Relaxed), 1);
+
+        // The same failure on a GET is retried.
+        let response = send_with_retry(client.get(format!("{}/hpst/get", server.base_url)), policy).await.unwrap();
+        assert_eq!(response.status(), 503);
+        assert_eq!(server.requests(|r| r.path == "/hpst/get").len(), 3);
+        assert_eq!(requests_sent.load(Ordering::Relaxed), 4);
+    }
+
+    #[tokio::test]
+    async fn prices_a_call_vertical_net_and_max_profit_and_loss() {
+        let expiration = date_in(20);
+        let mut chain = Vec::new();
+        for (strike, midpoint) in [(150.0, 3.0), (155.0, 1.0)] {
+            let mut contract = snapshot("HVRT", "call", strike, &expiration);
+            contract["last_quote"]["midpoint"] = json!(midpoint);
+            chain.push(contract);
+        }
+        serve_chain("HVRT", &chain);
+        let vertical = |long_strike: &str, short_strike: &str| {
+            query(json!({
+                "ticker_symbol": "HVRT",
+                "contract_type": "call",
+                "strategy": "vertical",
+                "long_strike": long_strike,
+                "short_strike": short_strike,
+            }))
+        };
+
+        // Bull call spread: pay 3 - 1 on a 5-wide spread.
+        let debit = vertical("150", "155").await;
+        let debit = &debit["vertical"];
+        assert_eq!(debit["expiration_date"], expiration);
+        assert_eq!(debit["long"]["ticker"], ticker_of(&chain[0]));
+        assert_eq!(debit["short"]["ticker"], ticker_of(&chain[1]));
+        assert_eq!(debit["net_premium"], 2.0);
+        assert_eq!(debit["net_type"], "debit");
+        assert_eq!(debit["width"], 5.0);
+        assert_eq!(debit["max_profit"], 3.0);
+        assert_eq!(debit["max_loss"], 2.0);
+
+        // Bear call spread: the same legs reversed collect the 2 as a credit.
+        let credit = vertical("155", "150").await;
+        let credit = &credit["vertical"];
+        assert_eq!(credit["net_premium"], -2.0);
+        assert_eq!(credit["net_type"], "credit");
+        assert_eq!(credit["max_profit"], 2.0);
+        assert_eq!(credit["max_loss"], 3.0);
+
+        // Without a quoted leg at one of the strikes there's no vertical.
+        assert!(vertical("150", "160").await["vertical"].is_null());
+    }
+
+    #[tokio::test]
+    async fn resolve_api_host_accepts_only_allowlisted_https_hosts() {
+        env::set_var("ALLOWED_API_HOSTS", "sandbox.polygon.io, proxy.example.com:8443");
+
+        assert_eq!(resolve_api_host("sandbox.polygon.io"), Ok("https://sandbox.polygon.io".to_string()));
+        assert_eq!(resolve_api_host(" https://Sandbox.Polygon.io/v3 "), Ok("https://sandbox.polygon.io".to_string()));
+        assert_eq!(resolve_api_host("proxy.example.com:8443"), Ok("https://proxy.example.com:8443".to_string()));
+        assert_eq!(
+            resolve_api_host("evil.example.com"),
+            Err("api_host \"evil.example.com\" is not in ALLOWED_API_HOSTS".to_string())
+        );
+        assert_eq!(
+            resolve_api_host("http://sandbox.polygon.io"),
+            Err("api_host \"http://sandbox.polygon.io\" must use https".to_string())
+        );
+        assert!(resolve_api_host("proxy.example.com").is_err());
+
+        let body = query(json!({ "ticker_symbol": "HAPH", "api_host": "evil.example.com" })).await;
+        assert_eq!(body["error"]["status_code"], 400);
+        assert_eq!(body["error"]["message"], "api_host \"evil.example.com\" is not in ALLOWED_API_HOSTS");
+        assert!(mock().requests(|r| is_listing_request(r, "HAPH")).is_empty());
+    }
+
+    #[tokio::test]
+    async fn counts_calls_and_puts_in_the_returned_mix() {
+        let expiration = date_in(17);
+        let calls = [145.0, 150.0, 155.0].map(|strike| snapshot("HMIX", "call", strike, &expiration));
+        let puts = [145.0, 150.0].map(|strike| snapshot("HMIX", "put", strike, &expiration));
+        let chain: Vec<Value> = calls.into_iter().chain(puts).collect();
+        serve_chain("HMIX", &chain);
+
+        let both = query(json!({ "ticker_symbol": "HMIX", "contract_type": "both" })).await;
+        assert_eq!(contracts(&both).len(), 5);
+        assert_eq!(both["metadata"]["counts"], json!({ "calls": 3, "puts": 2 }));
+
+        let puts = query(json!({ "ticker_symbol": "HMIX", "contract_type": "put" })).await;
+        assert_eq!(puts["metadata"]["counts"], json!({ "calls": 0, "puts": 2 }));
+    }
+
+    #[tokio::test]
+    async fn compares_iv_against_the_baseline_above_and_below() {
+        let expiration = date_in(19);
+        let ivs = [(145.0, json!(0.5)), (150.0, json!(0.25)), (155.0, json!(0.125)), (160.0, Value::Null)];
+        let chain: Vec<Value> = ivs
+            .iter()
+            .map(|(strike, iv)| {
+                let mut contract = snapshot("HBSL", "call", *strike, &expiration);
+                contract["implied_volatility"] = iv.clone();
+                contract
+            })
+            .collect();
+        serve_chain("HBSL", &chain);
+
+        let body = query(json!({ "ticker_symbol": "HBSL", "baseline_iv": "0.25" })).await;
+        let at_strike = |strike: f64| {
+            contracts(&body).iter().find(|c| c["strike_price"] == strike).unwrap()["iv_vs_baseline"].clone()
+        };
+        assert_eq!(at_strike(145.0), json!({ "difference": 0.25, "ratio": 2.0 }));
+        assert_eq!(at_strike(150.0), json!({ "difference": 0.0, "ratio": 1.0 }));
+        assert_eq!(at_strike(155.0), json!({ "difference": -0.125, "ratio": 0.5 }));
+        assert!(at_strike(160.0).is_null());
+
+        let above = query(json!({ "ticker_symbol": "HBSL", "baseline_iv": "0.25", "only_above_baseline": true })).await;
+        let strikes: Vec<f64> = contracts(&above).iter().map(|c| c["strike_price"].as_f64().unwrap()).collect();
+        assert_eq!(strikes, vec![145.0]);
+    }
+
+    #[tokio::test]
+    async fn times_out_a_slow_second_listing_page_on_its_own() {
+        let expiration = date_in(15);
+        let server = mock();
+        // Each page of HSLO takes 200ms: over 350ms together, but every page
+        // fits the per-page timeout.
+        let slow_ok = [snapshot("HSLO", "call", 20.0, &expiration), snapshot("HSLO", "call", 22.0, &expiration)];
+        let entries = [listing_entry(&slow_ok[0]), listing_entry(&slow_ok[1])];
+        let next_url = format!("{}/v3/reference/options/contracts?cursor=HSLO2", server.base_url);
+        server.route(move |request| {
+            let body = if is_listing_request(request, "HSLO") {
+                json!({ "status": "OK", "results": [entries[0]], "next_url": next_url })
+            } else if request.query.get("cursor").is_some_and(|c| c == "HSLO2") {
+                json!({ "status": "OK", "results": [entries[1]] })
+            } else {
+                return None;
+            };
+            std::thread::sleep(StdDuration::from_millis(200));
+            Some((200, body.to_string()))
+        });
+        serve_chain("HSLO", &slow_ok);
+
+        let body = query(json!({ "ticker_symbol": "HSLO", "page_timeout_ms": "350" })).await;
+        assert_eq!(contracts(&body).len(), 2);
+
+        // HSLW's second page stalls; each attempt at it is cut off after
+        // 350ms instead of holding the listing for the full three seconds.
+        let stalled = snapshot("HSLW", "call", 30.0, &expiration);
+        let entry = listing_entry(&stalled);
+        let next_url = format!("{}/v3/reference/options/contracts?cursor=HSLW2", server.base_url);
+        server.route(move |request| {
+            if is_listing_request(request, "HSLW") {
+                return Some((200, json!({ "status": "OK", "results": [entry], "next_url": next_url }).to_string()));
+            }
+            request.query.get("cursor").is_some_and(|c| c == "HSLW2").then(|| {
+                std::thread::sleep(StdDuration::from_secs(3));
+                (200, json!({ "status": "OK", "results": [] }).to_string())
+            })
+        });
+        serve_chain("HSLW", &[stalled]);
+
+        let payload = json!({
+            "ticker_symbol": "HSLW",
+            "api_key": "test-key",
+            "page_timeout_ms": "350",
+            "max_retries": "1",
+            "retry_base_delay_ms": "1",
+        });
+        let started = Instant::now();
+        let Err(error) = function_handler(LambdaEvent::new(payload, Context::default())).await else {
+            panic!("the stalled page didn't time out");
+        };
+
+        assert_eq!(error.to_string(), "Polygon request timed out after 2 attempt(s)");
+        assert_eq!(mock().requests(|r| r.query.get("cursor").is_some_and(|c| c == "HSLW2")).len(), 2);
+        assert!(started.elapsed() < StdDuration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn keeps_the_top_two_most_liquid_contracts_per_expiration() {
+        let (near, far) = (date_in(10), date_in(24));
+        let open_interest = [
+            (&near, 145.0, 100),
+            (&near, 150.0, 5000),
+            (&near, 155.0, 2000),
+            (&far, 145.0, 3000),
+            (&far, 150.0, 50),
+            (&far, 155.0, 900),
+        ];
+        let chain: Vec<Value> = open_interest
+            .iter()
+            .map(|(expiration, strike, oi)| {
+                let mut contract = snapshot("HPXT", "call", *strike, expiration);
+                contract["open_interest"] = json!(oi);
+                contract
+            })
+            .collect();
+        serve_chain("HPXT", &chain);
+
+        let body = query(json!({ "ticker_symbol": "HPXT", "per_expiration_top_n": "2" })).await;
+
+        let mut kept: Vec<(String, f64)> = contracts(&body)
+            .iter()
+            .map(|c| (c["expiration_date"].as_str().unwrap().to_string(), c["strike_price"].as_f64().unwrap()))
+            .collect();
+        kept.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        assert_eq!(kept, vec![(near.clone(), 150.0), (near, 155.0), (far.clone(), 145.0), (far, 155.0)]);
+    }
+
+    #[test]
+    fn computes_hedge_shares_from_delta_and_multiplier() {
+        let contract = |delta: Value, shares_per_contract: Value| {
+            json!({
+                "details": { "shares_per_contract": shares_per_contract },
+                "greeks": { "delta": delta },
+            })
+        };
+        assert_eq!(hedge_shares(&contract(json!(0.45), json!(100))), Some(45));
+        assert_eq!(hedge_shares(&contract(json!(-0.316), json!(100))), Some(-32));
+        assert_eq!(hedge_shares(&contract(json!(0.52), json!(10))), Some(5));
+        assert_eq!(hedge_shares(&contract(Value::Null, json!(100))), None);
+        assert_eq!(hedge_shares(&contract(json!(0.45), Value::Null)), None);
+    }
+
+    #[tokio::test]
+    async fn flags_a_spot_older_than_max_spot_age_secs() {
+        const SECOND: i64 = 1_000_000_000;
+        let mut contract = snapshot("HSPA", "call", 150.0, &date_in(22));
+        contract["underlying_asset"]["last_updated"] = json!(900 * SECOND);
+        let options = |max_spot_age_secs: Option<i64>, fetched_spot_updated_ns: Option<i64>| FormatOptions {
+            max_spot_age_secs,
+            fetched_spot_updated_ns,
+            now_ns: 1000 * SECOND,
+            ..format_options(None)
+        };
+
+        // The snapshot's spot is 100s old.
+        assert_eq!(is_spot_stale(&contract, Some("snapshot"), &options(Some(60), None)), Some(true));
+        assert_eq!(is_spot_stale(&contract, Some("snapshot"), &options(Some(100), None)), Some(false));
+        assert_eq!(is_spot_stale(&contract, Some("snapshot"), &options(None, None)), None);
+        // A fetched spot is judged by its own timestamp.
+        assert_eq!(is_spot_stale(&contract, Some("spot_fetch"), &options(Some(60), Some(990 * SECOND))), Some(false));
+        assert_eq!(is_spot_stale(&contract, Some("spot_fetch"), &options(Some(60), None)), None);
+        assert_eq!(is_spot_stale(&contract, None, &options(Some(60), None)), None);
+
+        let now_ns = Utc::now().timestamp_nanos_opt().unwrap();
+        contract["underlying_asset"]["last_updated"] = json!(now_ns - 600 * SECOND);
+        serve_chain("HSPA", &[contract]);
+
+        let stale = query(json!({ "ticker_symbol": "HSPA", "max_spot_age_secs": "60" })).await;
+        assert_eq!(contracts(&stale)[0]["spot_stale"], true);
+        assert!(stale["warnings"].as_array().unwrap().contains(&json!(
+            "1 contracts were computed from an underlying price older than max_spot_age_secs"
+        )));
+        let fresh = query(json!({ "ticker_symbol": "HSPA", "max_spot_age_secs": "3600" })).await;
+        assert_eq!(contracts(&fresh)[0]["spot_stale"], false);
+    }
+
+    #[tokio::test]
+    async fn rates_assignment_risk_high_when_deep_itm_near_expiry() {
+        assert_eq!(assess_assignment_risk(Some("ITM"), Some(2), Some(0.05)), Some("high"));
+        assert_eq!(assess_assignment_risk(Some("ITM"), Some(2), Some(1.5)), Some("medium"));
+        assert_eq!(assess_assignment_risk(Some("ITM"), Some(20), Some(0.05)), Some("medium"));
+        assert_eq!(assess_assignment_risk(Some("ITM"), Some(20), None), Some("low"));
+        assert_eq!(assess_assignment_risk(Some("OTM"), Some(0), Some(0.0)), Some("low"));
+        assert_eq!(assess_assignment_risk(None, Some(2), Some(0.05)), None);
+
+        // Spot is 150: the 100 call is $50 ITM with $0.05 of extrinsic left,
+        // the 250 call is far OTM.
+        let mut deep_itm = snapshot("HASG", "call", 100.0, &date_in(1));
+        deep_itm["last_quote"]["midpoint"] = json!(50.05);
+        let far_otm = snapshot("HASG", "call", 250.0, &date_in(25));
+        serve_chain("HASG", &[deep_itm, far_otm]);
+
+        let body = query(json!({ "ticker_symbol": "HASG" })).await;
+        let risk = |strike: f64| {
+            contracts(&body).iter().find(|c| c["strike_price"] == strike).unwrap()["assignment_risk"].clone()
+        };
+
+        assert_eq!(risk(100.0), "high");
+        assert_eq!(risk(250.0), "low");
+    }
+
+    #[tokio::test]
+    async fn strike_source_picks_which_strike_to_trust() {
+        // The snapshot carries an adjusted 149.5 strike; the listing still
+        // says 150.
+        let mut contract = snapshot("HSRC", "call", 150.0, &date_in(21));
+        let entry = listing_entry(&contract);
+        contract["details"]["strike_price"] = json!(149.5);
+        mock().route(move |request| {
+            is_listing_request(request, "HSRC")
+                .then(|| (200, json!({ "status": "OK", "results": [entry] }).to_string()))
+        });
+        serve_chain("HSRC", &[contract]);
+        let strike = |strike_source: Value| async move {
+            let body = query(json!({ "ticker_symbol": "HSRC", "strike_source": strike_source })).await;
+            contracts(&body)[0]["strike_price"].clone()
+        };
+
+        assert_eq!(strike(Value::Null).await, 149.5);
+        assert_eq!(strike(json!("snapshot")).await, 149.5);
+        assert_eq!(strike(json!("listing")).await, 150.0);
+
+        let body = query(json!({ "ticker_symbol": "HSRC", "strike_source": "both" })).await;
+        assert_eq!(body["error"]["field"], "strike_source");
+    }
+
+    #[tokio::test]
+    async fn omits_expiration_fields_for_a_malformed_expiration_date() {
+        let mut contract = snapshot("HBDX", "call", 150.0, &date_in(12));
+        contract["details"]["expiration_date"] = json!("10/20/2026");
+        let ticker = ticker_of(&contract);
+        serve_chain("HBDX", &[contract]);
+
+        let body = query(json!({ "ticker_symbol": "HBDX" })).await;
+
+        let contract = &contracts(&body)[0];
+        assert_eq!(contract["ticker"], ticker);
+        assert_eq!(contract["expiration_date"], "10/20/2026");
+        for field in ["days_to_expiration", "expiration_class", "is_0dte", "expected_move"] {
+            assert!(contract.get(field).is_none(), "{}", field);
+        }
+        assert!(body["warnings"].as_array().unwrap().contains(&json!(format!(
+            "{}: unparseable expiration_date \"10/20/2026\"; expiration fields omitted",
+            ticker
+        ))));
+    }
+
+    #[tokio::test]
+    async fn require_complete_drops_a_sparse_contract() {
+        let complete = snapshot("HRQC", "call", 150.0, &date_in(23));
+        assert!(has_complete_data(&complete));
+        let mut sparse = snapshot("HRQC", "call", 155.0, &date_in(23));
+        sparse["greeks"].as_object_mut().unwrap().remove("vega");
+        assert!(!has_complete_data(&sparse));
+        let mut one_sided = complete.clone();
+        one_sided["last_quote"]["bid"] = json!(0.0);
+        assert!(!has_complete_data(&one_sided));
+        serve_chain("HRQC", &[complete, sparse]);
+
+        let strict = query(json!({ "ticker_symbol": "HRQC", "require_complete": true })).await;
+        let strikes: Vec<f64> = contracts(&strict).iter().map(|c| c["strike_price"].as_f64().unwrap()).collect();
+        assert_eq!(strikes, vec![150.0]);
+        assert!(strict["warnings"]
+            .as_array()
+            .unwrap()
+            .contains(&json!("1 contracts with incomplete data were dropped (require_complete)")));
+
+        let lenient = query(json!({ "ticker_symbol": "HRQC" })).await;
+        assert_eq!(contracts(&lenient).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn orders_the_atm_term_structure_by_expiration() {
+        let (near, far) = (date_in(7), date_in(28));
+        let with_iv = |contract_type: &str, strike: f64, expiration: &str, iv: f64| {
+            let mut contract = snapshot("HTRM", contract_type, strike, expiration);
+            contract["implied_volatility"] = json!(iv);
+            contract
+        };
+        // Spot is 150. The far expiration comes first in the listing, and its
+        // ATM IV averages the call and the put at 150.
+        serve_chain(
+            "HTRM",
+            &[
+                with_iv("call", 150.0, &far, 0.25),
+                with_iv("put", 150.0, &far, 0.5),
+                with_iv("call", 160.0, &far, 0.9),
+                with_iv("call", 145.0, &near, 0.25),
+                with_iv("call", 151.0, &near, 0.5),
+            ],
+        );
+
+        let payload = json!({ "ticker_symbol": "HTRM", "contract_type": "both", "include_term_structure": true });
+        let body = query(payload).await;
+
+        assert_eq!(
+            body["term_structure"],
+            json!([
+                { "expiration": near, "dte": 7, "atm_iv": 0.5 },
+                { "expiration": far, "dte": 28, "atm_iv": 0.375 },
+            ])
+        );
+    }
+
+    #[tokio::test]
+    async fn a_scheduled_prewarm_fills_the_response_cache() {
+        for underlying in ["HPWA", "HPWB"] {
+            serve_chain(underlying, &[snapshot(underlying, "call", 60.0, &date_in(10))]);
+        }
+        let event = json!({
+            "source": "aws.events",
+            "detail-type": "Scheduled Event",
+            "detail": { "tickers": "hpwa, HPWB", "api_key": "test-key", "limit": "5" },
+        });
+
+        let summary = invoke(event).await;
+
+        assert_eq!(summary, json!({ "prewarmed": ["HPWA", "HPWB"], "warnings": [] }));
+        for underlying in ["HPWA", "HPWB"] {
+            let payload = json!({ "ticker_symbol": underlying, "limit": "5" });
+            let key = response_cache_key(&serde_json::from_value(payload).unwrap(), "test-key");
+            assert!(response_cache().get(&key, StdDuration::from_secs(60)).is_some(), "{}", underlying);
+        }
+
+        // A later request with the same parameters is answered from memory.
+        let cached = query(json!({ "ticker_symbol": "HPWA", "limit": "5", "max_stale_secs": "60" })).await;
+        assert_eq!(cached["metadata"]["cache"]["hit"], true);
+        assert_eq!(contracts(&cached).len(), 1);
+        assert_eq!(mock().requests(|r| r.path.starts_with(&snapshot_path_prefix("HPWA"))).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn strike_increment_keeps_only_five_dollar_strikes() {
+        let strike = |strike: f64| json!({ "strike_price": strike });
+        assert!(is_strike_multiple(&strike(155.0001), 5.0));
+        assert!(is_strike_multiple(&strike(2.5), 0.5));
+        assert!(!is_strike_multiple(&strike(152.5), 5.0));
+        assert!(!is_strike_multiple(&json!({}), 5.0));
+
+        let expiration = date_in(18);
+        let grid = [140.0, 142.5, 145.0, 147.5, 150.0, 151.0, 152.5, 155.0, 157.5];
+        let chain: Vec<Value> = grid.iter().map(|&k| snapshot("HINC", "call", k, &expiration)).collect();
+        serve_chain("HINC", &chain);
+
+        let body = query(json!({ "ticker_symbol": "HINC", "strike_increment": "5" })).await;
+
+        let strikes: Vec<f64> = contracts(&body).iter().map(|c| c["strike_price"].as_f64().unwrap()).collect();
+        assert_eq!(strikes, vec![140.0, 145.0, 150.0, 155.0]);
+    }
+
+    #[tokio::test]
+    async fn count_before_filter_picks_expirations_ahead_of_the_filters() {
+        let (first, second, third) = (date_in(5), date_in(12), date_in(19));
+        // Only the nearest expiration's contract falls outside the gamma band.
+        let mut below_band = snapshot("HNEX", "call", 150.0, &first);
+        below_band["greeks"]["gamma"] = json!(0.01);
+        let chain = [below_band, snapshot("HNEX", "call", 150.0, &second), snapshot("HNEX", "call", 150.0, &third)];
+        serve_chain("HNEX", &chain);
+        let expirations = |count_before_filter: bool| async move {
+            let body = query(json!({
+                "ticker_symbol": "HNEX",
+                "num_expirations": "1",
+                "min_gamma": "0.015",
+                "count_before_filter": count_before_filter,
+            }))
+            .await;
+            contracts(&body).iter().map(|c| c["expiration_date"].as_str().unwrap().to_string()).collect::<Vec<_>>()
+        };
+
+        // By default the filtered-out expiration doesn't count toward N.
+        assert_eq!(expirations(false).await, vec![second]);
+        // Counted first, the nearest expiration is chosen and then emptied.
+        assert!(expirations(true).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn resolved_request_shows_the_expanded_vertical() {
+        let expiration = date_in(26);
+        serve_chain(
+            "HRSV",
+            &[
+                snapshot("HRSV", "call", 150.0, &expiration),
+                snapshot("HRSV", "call", 152.5, &expiration),
+                snapshot("HRSV", "call", 155.0, &expiration),
+            ],
+        );
+
+        let body = query(json!({
+            "ticker_symbol": "hrsv",
+            "strategy": "vertical",
+            "long_strike": "150",
+            "short_strike": "155",
+            "include_resolved_request": true,
+        }))
+        .await;
+
+        let resolved = &body["resolved_request"];
+        assert_eq!(resolved["ticker_symbol"], "HRSV");
+        assert_eq!(resolved["strategy"], "vertical");
+        assert_eq!(resolved["contract_type"], "call");
+        assert_eq!(resolved["strikes"], json!([150.0, 155.0]));
+        assert_eq!(resolved["long_strike"], "150");
+        assert_eq!(resolved["short_strike"], "155");
+        assert!(resolved.get("api_key").is_none());
+        assert_eq!(contracts(&body).len(), 2);
+
+        // Sent back as a request, it reproduces the same contracts.
+        let replayed = query(resolved.clone()).await;
+        assert_eq!(contracts(&replayed), contracts(&body));
+        assert_eq!(replayed["vertical"], body["vertical"]);
+    }
+
+    #[test]
+    fn computes_premium_yield_from_known_premium_and_strike() {
+        assert_eq!(compute_premium_yield(Some(3.0), Some(150.0)), Some(0.02));
+        assert_eq!(compute_premium_yield(Some(1.25), Some(50.0)), Some(0.025));
+        assert_eq!(compute_premium_yield(Some(1.25), Some(0.0)), None);
+        assert_eq!(compute_premium_yield(None, Some(150.0)), None);
+        assert_eq!(compute_premium_yield(Some(1.25), None), None);
+
+        let formatted = format_contract(&snapshot("TPYD", "call", 125.0, &date_in(20)), &format_options(None));
+        assert_eq!(formatted.premium_yield, Some(0.01));
+    }
+
+    #[tokio::test]
+    async fn rejects_an_invalid_top_n() {
+        let expiration = date_in(17);
+        let chain: Vec<Value> = [40.0, 41.0, 42.0].map(|strike| snapshot("HTPN", "call", strike, &expiration)).to_vec();
+        serve_chain("HTPN", &chain);
+
+        let body = query(json!({ "ticker_symbol": "HTPN", "top_n": 2 })).await;
+        assert_eq!(contracts(&body).len(), 2);
+
+        for top_n in [json!("five"), json!(0), json!("0"), json!(-1), json!(2.5)] {
+            let body = query(json!({ "ticker_symbol": "HTPN", "top_n": top_n })).await;
+
+            assert_eq!(body["error"]["status_code"], 400, "top_n: {}", top_n);
+            assert_eq!(body["error"]["field"], "top_n");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_an_invalid_max_cost() {
+        serve_chain("HBGT", &[snapshot("HBGT", "call", 150.0, &date_in(11))]);
+
+        // Priced at the 1.30 ask, the contract costs $130.
+        let body = query(json!({ "ticker_symbol": "HBGT", "max_cost": 131 })).await;
+        assert_eq!(contracts(&body).len(), 1);
+        let body = query(json!({ "ticker_symbol": "HBGT", "max_cost": 129.5 })).await;
+        assert!(contracts(&body).is_empty());
+
+        for max_cost in [json!("$500"), json!("-1"), json!(-500), json!("NaN"), json!(true)] {
+            let body = query(json!({ "ticker_symbol": "HBGT", "max_cost": max_cost })).await;
+
+            assert_eq!(body["error"]["status_code"], 400, "max_cost: {}", max_cost);
+            assert_eq!(body["error"]["field"], "max_cost");
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_an_invalid_gamma_band() {
+        serve_chain("HGMV", &[snapshot("HGMV", "call", 150.0, &date_in(14))]);
+
+        // Numeric bounds work like string ones; the fixture's gamma is 0.02.
+        let body = query(json!({ "ticker_symbol": "HGMV", "min_gamma": 0.01, "max_gamma": 0.03 })).await;
+        assert_eq!(contracts(&body).len(), 1);
+
+        for (band, field) in [
+            (json!({ "min_gamma": "high" }), "min_gamma"),
+            (json!({ "max_gamma": "0.o3" }), "max_gamma"),
+            (json!({ "min_gamma": "inf" }), "min_gamma"),
+            (json!({ "min_gamma": 0.04, "max_gamma": "0.02" }), "min_gamma"),
+        ] {
+            let mut payload = band.clone();
+            payload["ticker_symbol"] = json!("HGMV");
+            let body = query(payload).await;
+
+            assert_eq!(body["error"]["status_code"], 400, "band: {}", band);
+            assert_eq!(body["error"]["field"], field, "band: {}", band);
+        }
+        let body = query(json!({ "ticker_symbol": "HGMV", "min_gamma": 0.04, "max_gamma": 0.02 })).await;
+        assert_eq!(body["error"]["message"], "min_gamma 0.04 is above max_gamma 0.02");
+    }
+}